@@ -22,6 +22,7 @@ use bevy_ort::{
         modnet_inference,
         modnet_output_to_luma_images,
         images_to_modnet_input,
+        ModnetConfig,
     },
     OrtSession,
     Session,
@@ -76,7 +77,7 @@ fn images_to_modnet_input_benchmark(c: &mut Criterion) {
             group.bench_with_input(BenchmarkId::from_parameter(format!("{}x{}", width, height)), &images, |b, images| {
                 let views = images.iter().map(|image| image).collect::<Vec<_>>();
 
-                b.iter(|| images_to_modnet_input(views.as_slice(), Some((*width, *height))));
+                b.iter(|| images_to_modnet_input(views.as_slice(), Some((*width, *height)), &ModnetConfig::default()));
             });
         });
 }
@@ -104,7 +105,7 @@ fn modnet_output_to_luma_images_benchmark(c: &mut Criterion) {
 
     MAX_RESOLUTIONS.iter()
         .for_each(|size_limit| {
-            let input = images_to_modnet_input(&[&image; STREAM_COUNT], size_limit.clone().into());
+            let input = images_to_modnet_input(&[&image; STREAM_COUNT], size_limit.clone().into(), &ModnetConfig::default());
             let input_values = inputs!["input" => input.view()].map_err(|e| e.to_string()).unwrap();
 
             let outputs = session.run(input_values).map_err(|e| e.to_string());
@@ -144,7 +145,7 @@ fn modnet_inference_benchmark(c: &mut Criterion) {
         group.throughput(Throughput::Elements(1));
         group.bench_with_input(BenchmarkId::from_parameter(format!("{}x{}", width, height)), &(width, height), |b, _| {
             b.iter(|| {
-                modnet_inference(&session, &[&image], Some((*width, *height)))
+                modnet_inference(&session, &[&image], Some((*width, *height)), &ModnetConfig::default())
             });
         });
     });
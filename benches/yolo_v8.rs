@@ -22,6 +22,8 @@ use bevy_ort::{
         prepare_input,
         process_output,
         yolo_inference,
+        YoloConfig,
+        YoloResize,
     },
     OrtSession,
     Session,
@@ -70,7 +72,7 @@ fn prepare_input_benchmark(c: &mut Criterion) {
 
             group.throughput(Throughput::Elements(1));
             group.bench_with_input(BenchmarkId::from_parameter(format!("{}x{}", width, height)), &image, |b, image| {
-                b.iter(|| prepare_input(&image, MODEL_WIDTH, MODEL_HEIGHT));
+                b.iter(|| prepare_input(&image, MODEL_WIDTH, MODEL_HEIGHT, YoloResize::default()));
             });
         });
 }
@@ -98,7 +100,7 @@ fn process_output_benchmark(c: &mut Criterion) {
                 RenderAssetUsages::all(),
             );
 
-            let input = prepare_input(&image, MODEL_WIDTH, MODEL_HEIGHT);
+            let (input, transform) = prepare_input(&image, MODEL_WIDTH, MODEL_HEIGHT, YoloResize::default());
             let input_values = inputs!["images" => &input.as_standard_layout()].map_err(|e| e.to_string()).unwrap();
 
             let outputs = session.run(input_values).map_err(|e| e.to_string());
@@ -107,7 +109,7 @@ fn process_output_benchmark(c: &mut Criterion) {
 
             group.throughput(Throughput::Elements(1));
             group.bench_with_input(BenchmarkId::from_parameter(format!("{}x{}", width, height)), &output_value, |b, output_value| {
-                b.iter(|| process_output(output_value, *width, *height, MODEL_WIDTH, MODEL_HEIGHT));
+                b.iter(|| process_output(output_value, &transform, &YoloConfig::default()));
             });
         });
 }
@@ -138,7 +140,7 @@ fn inference_benchmark(c: &mut Criterion) {
         group.throughput(Throughput::Elements(1));
         group.bench_with_input(BenchmarkId::from_parameter(format!("{}x{}", width, height)), &(width, height), |b, _| {
             b.iter(|| {
-                yolo_inference(&session, &image, 0.5)
+                yolo_inference(&session, &image, &YoloConfig::default())
             });
         });
     });
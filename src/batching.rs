@@ -0,0 +1,298 @@
+use std::{
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    inference::InferenceHandle,
+    BevyOrtError,
+    Onnx,
+    OrtSession,
+};
+
+
+/// a model resource whose requests can be gathered into one batched session run
+pub trait BatchedModel: Resource {
+    type Input: Send + Sync + 'static;
+    type Output: Send + Sync + 'static;
+
+    fn onnx(&self) -> &Handle<Onnx>;
+
+    /// whether the request can be batched this frame, e.g. its input image has loaded
+    fn ready(&self, _input: &Self::Input, _images: &Assets<Image>) -> bool {
+        true
+    }
+
+    /// runs one batch with `OrtSession::run_with`, returning one output per input in order
+    fn infer(
+        &self,
+        session: &OrtSession,
+        inputs: &[&Self::Input],
+        images: &mut Assets<Image>,
+        handle: Option<&InferenceHandle>,
+    ) -> Result<Vec<Self::Output>, String>;
+}
+
+
+/// gathers `BatchRequest<M>` components across entities and frames into batched inference
+pub struct BatchingPlugin<M: BatchedModel> {
+    pub max_batch_size: usize,
+    pub max_wait: Duration,
+    /// per batch run timeout
+    pub timeout: Option<Duration>,
+    _model: PhantomData<fn() -> M>,
+}
+
+impl<M: BatchedModel> Default for BatchingPlugin<M> {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 16,
+            max_wait: Duration::from_millis(50),
+            timeout: None,
+            _model: PhantomData,
+        }
+    }
+}
+
+impl<M: BatchedModel> BatchingPlugin<M> {
+    pub fn new(max_batch_size: usize, max_wait: Duration) -> Self {
+        Self {
+            max_batch_size,
+            max_wait,
+            timeout: None,
+            _model: PhantomData,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<M: BatchedModel> Plugin for BatchingPlugin<M> {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(BatchScheduler::<M> {
+            max_batch_size: self.max_batch_size,
+            max_wait: self.max_wait,
+            timeout: self.timeout,
+            _model: PhantomData,
+        });
+        app.add_systems(PreUpdate, batch_inference_system::<M>);
+    }
+}
+
+
+/// a batch is dispatched once `max_batch_size` requests are pending or the oldest has waited `max_wait`
+///
+/// `max_batch_size` is further limited by a fixed batch dimension on the model's first input.
+/// requests whose entity has a cancelled `InferenceHandle` are dropped before batching.
+#[derive(Resource)]
+pub struct BatchScheduler<M: BatchedModel> {
+    pub max_batch_size: usize,
+    pub max_wait: Duration,
+    pub timeout: Option<Duration>,
+    _model: PhantomData<fn() -> M>,
+}
+
+impl<M: BatchedModel> BatchScheduler<M> {
+    pub fn batch_size(&self, session: &OrtSession) -> usize {
+        let model_batch_size = session.inputs()
+            .first()
+            .and_then(|input| input.input_type.tensor_dimensions())
+            .and_then(|dimensions| dimensions.first().copied());
+
+        clamp_batch_size(self.max_batch_size, model_batch_size)
+    }
+}
+
+
+/// `max_batch_size` limited by a fixed (positive) model batch dimension, at least 1
+fn clamp_batch_size(max_batch_size: usize, model_batch_size: Option<i64>) -> usize {
+    let batch_size = match model_batch_size.filter(|&batch| batch > 0) {
+        Some(model_batch_size) => max_batch_size.min(model_batch_size as usize),
+        None => max_batch_size,
+    };
+
+    batch_size.max(1)
+}
+
+/// lengths of the batches to dispatch from request times sorted oldest first
+///
+/// full batches are always dispatched, a partial batch only once its oldest request has waited `max_wait`.
+fn dispatch_batches(
+    requested_at: &[Instant],
+    batch_size: usize,
+    max_wait: Duration,
+    now: Instant,
+) -> Vec<usize> {
+    let batch_size = batch_size.max(1);
+
+    requested_at.chunks(batch_size)
+        .take_while(|batch| batch.len() == batch_size || now.duration_since(batch[0]) >= max_wait)
+        .map(|batch| batch.len())
+        .collect()
+}
+
+
+#[derive(Component)]
+pub struct BatchRequest<M: BatchedModel> {
+    pub input: M::Input,
+    pub requested_at: Instant,
+    _model: PhantomData<fn() -> M>,
+}
+
+impl<M: BatchedModel> BatchRequest<M> {
+    pub fn new(input: M::Input) -> Self {
+        Self {
+            input,
+            requested_at: Instant::now(),
+            _model: PhantomData,
+        }
+    }
+}
+
+/// inserted on the requesting entity, replacing its `BatchRequest<M>`
+#[derive(Component)]
+pub struct BatchResult<M: BatchedModel> {
+    pub output: Result<M::Output, String>,
+    _model: PhantomData<fn() -> M>,
+}
+
+
+fn batch_inference_system<M: BatchedModel>(
+    mut commands: Commands,
+    model: Res<M>,
+    scheduler: Res<BatchScheduler<M>>,
+    onnx_assets: Res<Assets<Onnx>>,
+    mut images: ResMut<Assets<Image>>,
+    requests: Query<(Entity, &BatchRequest<M>, Option<&InferenceHandle>)>,
+) {
+    let mut pending = Vec::new();
+    for (entity, request, handle) in requests.iter() {
+        if handle.is_some_and(|handle| handle.take_cancelled()) {
+            commands.entity(entity)
+                .remove::<BatchRequest<M>>()
+                .insert(BatchResult::<M> {
+                    output: Err(BevyOrtError::Cancelled.to_string()),
+                    _model: PhantomData,
+                });
+            continue;
+        }
+
+        if model.ready(&request.input, &images) {
+            pending.push((entity, request));
+        }
+    }
+
+    if pending.is_empty() {
+        return;
+    }
+
+    let Some(onnx) = onnx_assets.get(model.onnx()) else {
+        return;
+    };
+    let Ok(session_lock) = onnx.session.lock() else {
+        return;
+    };
+    let Some(session) = session_lock.as_ref() else {
+        return;
+    };
+
+    pending.sort_by_key(|(_, request)| request.requested_at);
+
+    let requested_at = pending.iter()
+        .map(|(_, request)| request.requested_at)
+        .collect::<Vec<_>>();
+    let batches = dispatch_batches(
+        &requested_at,
+        scheduler.batch_size(session),
+        scheduler.max_wait,
+        Instant::now(),
+    );
+
+    let mut remaining = pending.as_slice();
+    for batch_len in batches {
+        let (batch, rest) = remaining.split_at(batch_len);
+        remaining = rest;
+
+        let inputs = batch.iter()
+            .map(|(_, request)| &request.input)
+            .collect::<Vec<_>>();
+
+        let handle = scheduler.timeout
+            .map(|timeout| InferenceHandle::new().map(|handle| handle.with_timeout(timeout)))
+            .transpose()
+            .map_err(|e| e.to_string());
+
+        let outputs = handle
+            .and_then(|handle| model.infer(session, &inputs, &mut images, handle.as_ref()))
+            .and_then(|outputs| {
+                if outputs.len() == batch.len() {
+                    Ok(outputs)
+                } else {
+                    Err(format!("expected {} batch outputs, got {}", batch.len(), outputs.len()))
+                }
+            });
+
+        let results: Vec<Result<M::Output, String>> = match outputs {
+            Ok(outputs) => outputs.into_iter().map(Ok).collect(),
+            Err(e) => batch.iter().map(|_| Err(e.clone())).collect(),
+        };
+
+        for ((entity, _), output) in batch.iter().zip(results) {
+            commands.entity(*entity)
+                .remove::<BatchRequest<M>>()
+                .insert(BatchResult::<M> {
+                    output,
+                    _model: PhantomData,
+                });
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_size_is_clamped_to_fixed_model_batch() {
+        assert_eq!(clamp_batch_size(16, Some(4)), 4, "fixed model batch should limit the batch size.");
+        assert_eq!(clamp_batch_size(2, Some(4)), 2, "max batch size should limit the batch size.");
+        assert_eq!(clamp_batch_size(16, Some(-1)), 16, "dynamic model batch should not limit the batch size.");
+        assert_eq!(clamp_batch_size(16, None), 16, "unknown model batch should not limit the batch size.");
+        assert_eq!(clamp_batch_size(0, None), 1, "batch size should be at least 1.");
+    }
+
+    #[test]
+    fn test_dispatch_waits_for_full_batch_or_max_wait() {
+        let now = Instant::now();
+        let max_wait = Duration::from_millis(50);
+        let fresh = now - Duration::from_millis(10);
+        let stale = now - Duration::from_millis(100);
+
+        assert_eq!(
+            dispatch_batches(&[fresh; 5], 2, max_wait, now),
+            vec![2, 2],
+            "full batches should dispatch and a fresh partial batch should wait.",
+        );
+        assert_eq!(
+            dispatch_batches(&[stale, fresh, fresh], 4, max_wait, now),
+            vec![3],
+            "a partial batch should dispatch once its oldest request has waited max_wait.",
+        );
+        assert_eq!(
+            dispatch_batches(&[fresh], 4, max_wait, now),
+            Vec::<usize>::new(),
+            "a fresh partial batch should not dispatch.",
+        );
+        assert_eq!(
+            dispatch_batches(&[], 4, max_wait, now),
+            Vec::<usize>::new(),
+            "no requests should dispatch no batches.",
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    any::Any,
+    convert::Infallible,
+    fmt::Debug,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    prelude::*,
+    utils::HashMap,
+};
+use bytemuck::Pod;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Onnx;
+
+
+/// content hash of a model identity and its input tensors
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    /// `model` should be stable across runs when the cache is persisted, e.g. the model content hash
+    pub fn builder(model: &str) -> CacheKeyBuilder {
+        CacheKeyBuilder(Sha256::new()).bytes(model.as_bytes())
+    }
+
+    /// keys by the model's content hash, so a replaced model file invalidates persisted results
+    ///
+    /// sessions built without model bytes (e.g. `Onnx::from_session`) fall back to the asset path, then the asset id.
+    pub fn model(asset_server: &AssetServer, handle: &Handle<Onnx>, onnx: &Onnx) -> CacheKeyBuilder {
+        match (&onnx.sha256, asset_server.get_path(handle)) {
+            (Some(sha256), _) => Self::builder(sha256),
+            (None, Some(path)) => Self::builder(&path.to_string()),
+            (None, None) => Self::builder(&format!("{:?}", handle.id())),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+pub struct CacheKeyBuilder(Sha256);
+
+impl CacheKeyBuilder {
+    pub fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.update((bytes.len() as u64).to_le_bytes());
+        self.0.update(bytes);
+        self
+    }
+
+    pub fn tensor<T: Pod>(self, shape: &[usize], data: &[T]) -> Self {
+        let shape = shape.iter()
+            .flat_map(|dim| (*dim as u64).to_le_bytes())
+            .collect::<Vec<_>>();
+
+        self.bytes(&shape)
+            .bytes(bytemuck::cast_slice(data))
+    }
+
+    pub fn image(self, image: &Image) -> Self {
+        let size = image.texture_descriptor.size;
+        let format = format!("{:?}", image.texture_descriptor.format);
+
+        self.bytes(&[size.width, size.height, size.depth_or_array_layers].map(u32::to_le_bytes).concat())
+            .bytes(format.as_bytes())
+            .bytes(&image.data)
+    }
+
+    /// hashes the `Debug` representation, e.g. for config structs without hash maps
+    pub fn debug(self, value: &impl Debug) -> Self {
+        self.bytes(format!("{:?}", value).as_bytes())
+    }
+
+    pub fn finish(self) -> CacheKey {
+        CacheKey(format!("{:x}", self.0.finalize()))
+    }
+}
+
+
+struct CacheEntry {
+    value: Arc<dyn Any + Send + Sync>,
+    last_used: u64,
+}
+
+struct CacheState {
+    capacity: usize,
+    directory: Option<PathBuf>,
+    entries: HashMap<CacheKey, CacheEntry>,
+    tick: u64,
+}
+
+
+/// opt-in LRU cache of inference results, insert the resource to enable caching
+///
+/// consulted by the ECS inference systems before running a session. helpers can be wrapped
+/// directly, e.g. `yolo_inference_cached(&cache, CacheKey::model(..), session, image, &config)`.
+/// images such as modnet mattes are not serializable and are only cached in memory.
+#[derive(Resource, Clone)]
+pub struct InferenceCache(Arc<Mutex<CacheState>>);
+
+impl Default for InferenceCache {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+impl InferenceCache {
+    pub fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(CacheState {
+            capacity: capacity.max(1),
+            directory: None,
+            entries: HashMap::new(),
+            tick: 0,
+        })))
+    }
+
+    /// persists serializable results (see `get_or_run_persistent`) to `directory`
+    pub fn with_directory(self, directory: impl Into<PathBuf>) -> Self {
+        self.0.lock().unwrap().directory = Some(directory.into());
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().entries.clear();
+    }
+
+    pub fn get<T: Any + Clone>(&self, key: &CacheKey) -> Option<T> {
+        let mut state = self.0.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+
+        let entry = state.entries.get_mut(key)?;
+        let value = entry.value.downcast_ref::<T>()?.clone();
+        entry.last_used = tick;
+
+        Some(value)
+    }
+
+    pub fn insert<T: Any + Send + Sync>(&self, key: CacheKey, value: T) {
+        let mut state = self.0.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+
+        state.entries.insert(key, CacheEntry {
+            value: Arc::new(value),
+            last_used: tick,
+        });
+
+        while state.entries.len() > state.capacity {
+            let least_recent = state.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+
+            match least_recent {
+                Some(key) => state.entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    pub fn get_or_run<T: Any + Clone + Send + Sync>(
+        &self,
+        key: CacheKey,
+        run: impl FnOnce() -> T,
+    ) -> T {
+        if let Some(value) = self.get(&key) {
+            return value;
+        }
+
+        let value = run();
+        self.insert(key, value.clone());
+        value
+    }
+
+    /// only successful results are cached
+    pub fn try_get_or_run<T: Any + Clone + Send + Sync, E>(
+        &self,
+        key: CacheKey,
+        run: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+
+        let value = run()?;
+        self.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// like `get_or_run`, additionally reading and writing the persistence directory
+    pub fn get_or_run_persistent<T: Any + Clone + Send + Sync + Serialize + DeserializeOwned>(
+        &self,
+        key: CacheKey,
+        run: impl FnOnce() -> T,
+    ) -> T {
+        match self.try_get_or_run_persistent(key, || Ok::<T, Infallible>(run())) {
+            Ok(value) => value,
+            Err(e) => match e {},
+        }
+    }
+
+    pub fn try_get_or_run_persistent<T: Any + Clone + Send + Sync + Serialize + DeserializeOwned, E>(
+        &self,
+        key: CacheKey,
+        run: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+
+        if let Some(value) = self.read_persisted::<T>(&key) {
+            self.insert(key, value.clone());
+            return Ok(value);
+        }
+
+        let value = run()?;
+        self.write_persisted(&key, &value);
+        self.insert(key, value.clone());
+        Ok(value)
+    }
+
+    fn persisted_path(&self, key: &CacheKey) -> Option<PathBuf> {
+        let state = self.0.lock().unwrap();
+        state.directory
+            .as_ref()
+            .map(|directory| directory.join(format!("{}.bin", key.as_str())))
+    }
+
+    fn read_persisted<T: DeserializeOwned>(&self, key: &CacheKey) -> Option<T> {
+        let bytes = fs::read(self.persisted_path(key)?).ok()?;
+        bincode::deserialize(&bytes).ok()
+    }
+
+    fn write_persisted<T: Serialize>(&self, key: &CacheKey, value: &T) {
+        let Some(path) = self.persisted_path(key) else {
+            return;
+        };
+
+        let result = bincode::serialize(value)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+                }
+                fs::write(&path, bytes).map_err(|e| e.to_string())
+            });
+
+        if let Err(e) = result {
+            warn!("failed to persist inference cache entry {}: {}", path.display(), e);
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(input: &[f32]) -> CacheKey {
+        CacheKey::builder("models/test.onnx")
+            .tensor(&[input.len()], input)
+            .finish()
+    }
+
+    #[test]
+    fn test_key_depends_on_model_and_inputs() {
+        assert_eq!(key(&[1.0, 2.0]), key(&[1.0, 2.0]));
+        assert_ne!(key(&[1.0, 2.0]), key(&[1.0, 3.0]));
+
+        let other_model = CacheKey::builder("models/other.onnx")
+            .tensor(&[2], &[1.0f32, 2.0])
+            .finish();
+        assert_ne!(key(&[1.0, 2.0]), other_model);
+    }
+
+    #[test]
+    fn test_cached_results_skip_run() {
+        let cache = InferenceCache::new(4);
+
+        assert_eq!(cache.get_or_run(key(&[1.0]), || 1), 1);
+        assert_eq!(cache.get_or_run(key(&[1.0]), || 2), 1, "the cached result should be returned.");
+        assert_eq!(cache.try_get_or_run(key(&[2.0]), || Err::<i32, _>("failed")), Err("failed"));
+        assert!(cache.get::<i32>(&key(&[2.0])).is_none(), "failed results should not be cached.");
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = InferenceCache::new(2);
+
+        cache.insert(key(&[1.0]), 1);
+        cache.insert(key(&[2.0]), 2);
+        assert_eq!(cache.get::<i32>(&key(&[1.0])), Some(1));
+
+        cache.insert(key(&[3.0]), 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get::<i32>(&key(&[2.0])), None, "the least recently used entry should be evicted.");
+        assert_eq!(cache.get::<i32>(&key(&[1.0])), Some(1));
+        assert_eq!(cache.get::<i32>(&key(&[3.0])), Some(3));
+    }
+
+    #[test]
+    fn test_persistence() {
+        let directory = std::env::temp_dir().join(format!("bevy_ort_cache_test_{}", std::process::id()));
+
+        let cache = InferenceCache::new(4).with_directory(&directory);
+        assert_eq!(cache.get_or_run_persistent(key(&[1.0]), || vec![1.0f32, 2.0]), vec![1.0, 2.0]);
+
+        let reloaded = InferenceCache::new(4).with_directory(&directory);
+        assert_eq!(reloaded.get_or_run_persistent(key(&[1.0]), Vec::<f32>::new), vec![1.0, 2.0]);
+
+        fs::remove_dir_all(directory).ok();
+    }
+}
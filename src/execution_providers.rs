@@ -0,0 +1,175 @@
+use std::sync::{Arc, RwLock};
+
+use bevy::{
+    prelude::*,
+    asset::AssetPath,
+    utils::HashMap,
+};
+use ort::{
+    CoreMLExecutionProvider,
+    CPUExecutionProvider,
+    DirectMLExecutionProvider,
+    CUDAExecutionProvider,
+    ExecutionProvider,
+    ExecutionProviderDispatch,
+    OpenVINOExecutionProvider,
+    Session,
+    SessionBuilder,
+    TensorRTExecutionProvider,
+};
+
+
+/// outcome of registering the requested execution providers on a session builder
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionProviderReport {
+    pub requested: Vec<&'static str>,
+    pub registered: Vec<&'static str>,
+    pub failed: Vec<(&'static str, String)>,
+}
+
+impl ExecutionProviderReport {
+    pub fn log(&self, context: &str) {
+        info!(
+            "{}: requested execution providers {:?}, registered {:?}",
+            context,
+            self.requested,
+            self.registered,
+        );
+
+        for (provider, error) in &self.failed {
+            warn!("{}: execution provider {} unavailable: {}", context, provider, error);
+        }
+    }
+}
+
+
+/// execution providers registered (in priority order) on the ort environment, and so on every session ort commits
+///
+/// `startup` is filled in when `BevyOrtPlugin` is built, `sessions` (the environment's report) as each ONNX asset loads.
+#[derive(Resource, Clone)]
+pub struct ExecutionProviders {
+    providers: Arc<Vec<Arc<dyn ExecutionProvider + Send + Sync>>>,
+    environment: Arc<Vec<ExecutionProviderDispatch>>,
+    pub startup: ExecutionProviderReport,
+    sessions: Arc<RwLock<HashMap<AssetPath<'static>, ExecutionProviderReport>>>,
+}
+
+impl Default for ExecutionProviders {
+    // TODO: configurable execution providers via plugin settings
+    fn default() -> Self {
+        Self::empty()
+            .with_provider(CoreMLExecutionProvider::default())
+            .with_provider(CUDAExecutionProvider::default())
+            .with_provider(OpenVINOExecutionProvider::default())
+            .with_provider(DirectMLExecutionProvider::default())
+            .with_provider(TensorRTExecutionProvider::default())
+            .with_provider(CPUExecutionProvider::default())
+    }
+}
+
+impl ExecutionProviders {
+    pub fn empty() -> Self {
+        Self {
+            providers: Arc::new(Vec::new()),
+            environment: Arc::new(Vec::new()),
+            startup: ExecutionProviderReport::default(),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// appends a provider, lower priority than those already added
+    pub fn with_provider<E>(mut self, provider: E) -> Self
+    where
+        E: ExecutionProvider + Clone + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.providers).push(Arc::new(provider.clone()));
+        Arc::make_mut(&mut self.environment).push(provider.build());
+        self
+    }
+
+    /// commits the ort environment with these providers and fills in `startup`
+    ///
+    /// providers are only registered on the environment, which ort applies to every session it commits.
+    /// registering them on session builders as well would append each provider twice.
+    pub fn init_environment(&mut self) {
+        if let Err(e) = ort::init()
+            .with_execution_providers(self.environment.as_slice())
+            .commit()
+        {
+            warn!("failed to initialize ort environment: {}", e);
+        }
+
+        self.startup = self.probe();
+    }
+
+    /// registers each provider on a throwaway session builder, reporting which ones the environment can use
+    fn probe(&self) -> ExecutionProviderReport {
+        match Session::builder() {
+            Ok(builder) => self.register(&builder),
+            Err(e) => ExecutionProviderReport {
+                requested: self.requested(),
+                registered: Vec::new(),
+                failed: self.requested()
+                    .into_iter()
+                    .map(|provider| (provider, e.to_string()))
+                    .collect(),
+            },
+        }
+    }
+
+    pub fn requested(&self) -> Vec<&'static str> {
+        self.providers.iter()
+            .map(|provider| provider.as_str())
+            .collect()
+    }
+
+    /// registers each provider on the builder, falling through to the next provider on failure
+    fn register(&self, builder: &SessionBuilder) -> ExecutionProviderReport {
+        let mut report = ExecutionProviderReport {
+            requested: self.requested(),
+            ..default()
+        };
+
+        for provider in self.providers.iter() {
+            let name = provider.as_str();
+
+            if !provider.supported_by_platform() {
+                report.failed.push((name, "not supported on this platform".to_string()));
+                continue;
+            }
+
+            match provider.is_available() {
+                Ok(true) => {},
+                Ok(false) => {
+                    report.failed.push((name, "not included in this onnxruntime build".to_string()));
+                    continue;
+                },
+                Err(e) => {
+                    report.failed.push((name, e.to_string()));
+                    continue;
+                },
+            }
+
+            match provider.register(builder) {
+                Ok(()) => report.registered.push(name),
+                Err(e) => report.failed.push((name, e.to_string())),
+            }
+        }
+
+        report
+    }
+
+    pub fn session(&self, path: &AssetPath) -> Option<ExecutionProviderReport> {
+        self.sessions.read().unwrap()
+            .get(path)
+            .cloned()
+    }
+
+    pub fn sessions(&self) -> HashMap<AssetPath<'static>, ExecutionProviderReport> {
+        self.sessions.read().unwrap().clone()
+    }
+
+    pub(crate) fn insert_session(&self, path: AssetPath<'static>, report: ExecutionProviderReport) {
+        self.sessions.write().unwrap().insert(path, report);
+    }
+}
@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use bevy::{
+    prelude::*,
+    asset::LoadState,
+    core::FrameCount,
+    utils::HashMap,
+};
+
+#[cfg(feature = "modnet")]
+use crate::models::modnet::ModnetInput;
+use crate::policy::{
+    InferencePolicyPlugin,
+    InferencePolicySet,
+    InferenceStaleness,
+};
+
+
+/// plays numbered image files as a stream, a headless stand-in for camera input
+pub struct ImageSequencePlugin;
+impl Plugin for ImageSequencePlugin {
+    fn build(&self, app: &mut App) {
+        InferencePolicyPlugin::add_once(app);
+
+        app.add_systems(PreUpdate, advance_image_sequences.before(InferencePolicySet));
+
+        #[cfg(feature = "modnet")]
+        app.add_systems(
+            PreUpdate,
+            sync_modnet_inputs
+                .after(advance_image_sequences)
+                .before(InferencePolicySet),
+        );
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SequencePlayback {
+    Fps(f32),
+    /// advance once the entity's `InferenceStaleness` reports a run on the current frame
+    FollowInference,
+}
+
+impl Default for SequencePlayback {
+    fn default() -> Self {
+        SequencePlayback::Fps(30.0)
+    }
+}
+
+
+/// updates `image` (and a `Handle<Image>` or `ModnetInput` component on the same entity) as frames advance
+///
+/// frames are loaded `prefetch` ahead of the current frame and released once passed.
+/// advancing marks the entity's `InferenceStaleness` input as changed.
+#[derive(Component, Clone, Debug)]
+pub struct ImageSequence {
+    pub frames: Vec<String>,
+    pub playback: SequencePlayback,
+    pub looping: bool,
+    pub prefetch: usize,
+    pub image: Handle<Image>,
+    current: Option<usize>,
+    loading: HashMap<usize, Handle<Image>>,
+    since_advance: Duration,
+    shown_at_frame: u32,
+}
+
+impl ImageSequence {
+    pub fn new(frames: Vec<String>) -> Self {
+        Self {
+            frames,
+            playback: SequencePlayback::default(),
+            looping: false,
+            prefetch: 2,
+            image: Handle::default(),
+            current: None,
+            loading: HashMap::new(),
+            since_advance: Duration::ZERO,
+            shown_at_frame: 0,
+        }
+    }
+
+    /// expands a `#` padded pattern, e.g. `frames/frame_####.png` over `1..4` gives `frames/frame_0001.png`..
+    pub fn numbered(pattern: &str, numbers: impl IntoIterator<Item = usize>) -> Self {
+        let start = pattern.find('#');
+        let width = pattern.chars().filter(|c| *c == '#').count();
+
+        let frames = numbers.into_iter()
+            .map(|number| match start {
+                Some(start) => format!(
+                    "{}{:0width$}{}",
+                    &pattern[..start],
+                    number,
+                    &pattern[start + width..],
+                    width = width,
+                ),
+                None => pattern.to_string(),
+            })
+            .collect();
+
+        Self::new(frames)
+    }
+
+    pub fn with_playback(mut self, playback: SequencePlayback) -> Self {
+        self.playback = playback;
+        self
+    }
+
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    pub fn current_frame(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !self.looping && self.next_frame().is_none()
+    }
+
+    fn next_frame(&self) -> Option<usize> {
+        let next = self.current.map_or(0, |current| current + 1);
+
+        if next < self.frames.len() {
+            Some(next)
+        } else if self.looping && !self.frames.is_empty() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    fn frame_after(&self, frame: usize, offset: usize) -> Option<usize> {
+        let frame = frame + offset;
+
+        if frame < self.frames.len() {
+            Some(frame)
+        } else if self.looping {
+            Some(frame % self.frames.len())
+        } else {
+            None
+        }
+    }
+}
+
+
+fn advance_image_sequences(
+    asset_server: Res<AssetServer>,
+    frame_count: Res<FrameCount>,
+    time: Res<Time>,
+    mut sequences: Query<
+        (
+            &mut ImageSequence,
+            Option<&mut Handle<Image>>,
+            Option<&mut InferenceStaleness>,
+        ),
+    >,
+) {
+    for (mut sequence, handle, staleness) in sequences.iter_mut() {
+        let Some(next) = sequence.next_frame() else {
+            continue;
+        };
+
+        for offset in 0..=sequence.prefetch {
+            if let Some(frame) = sequence.frame_after(next, offset) {
+                if !sequence.loading.contains_key(&frame) {
+                    let image = asset_server.load(sequence.frames[frame].clone());
+                    sequence.loading.insert(frame, image);
+                }
+            }
+        }
+
+        sequence.since_advance += time.delta();
+
+        let due = match (sequence.current, sequence.playback) {
+            (None, _) => true,
+            (Some(_), SequencePlayback::Fps(fps)) => fps > 0.0 && sequence.since_advance.as_secs_f32() >= 1.0 / fps,
+            (Some(_), SequencePlayback::FollowInference) => match staleness.as_ref().and_then(|staleness| staleness.last_run_frame) {
+                Some(frame) => frame >= sequence.shown_at_frame,
+                None => false,
+            },
+        };
+
+        if !due {
+            continue;
+        }
+
+        let image = sequence.loading[&next].clone();
+        match asset_server.get_load_state(image.id()) {
+            Some(LoadState::Loaded) => {},
+            Some(LoadState::Failed) => {
+                warn!("skipping image sequence frame {}", sequence.frames[next]);
+                sequence.loading.remove(&next);
+                sequence.current = Some(next);
+                continue;
+            },
+            _ => continue,
+        }
+
+        sequence.current = Some(next);
+        sequence.image = image.clone();
+        sequence.since_advance = Duration::ZERO;
+        sequence.shown_at_frame = frame_count.0;
+
+        let prefetched = (0..=sequence.prefetch)
+            .filter_map(|offset| sequence.frame_after(next, offset))
+            .collect::<Vec<_>>();
+        sequence.loading.retain(|frame, _| prefetched.contains(frame));
+
+        if let Some(mut handle) = handle {
+            *handle = image;
+        }
+
+        if let Some(mut staleness) = staleness {
+            staleness.input_changed = true;
+        }
+    }
+}
+
+#[cfg(feature = "modnet")]
+fn sync_modnet_inputs(
+    mut sequences: Query<(&ImageSequence, &mut ModnetInput), Changed<ImageSequence>>,
+) {
+    for (sequence, mut modnet_input) in sequences.iter_mut() {
+        // only touch the input on a new frame, its change detection triggers inference
+        if modnet_input.image != sequence.image {
+            modnet_input.image = sequence.image.clone();
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbered_frames() {
+        let sequence = ImageSequence::numbered("frames/frame_####.png", 8..11);
+
+        assert_eq!(sequence.frames, vec![
+            "frames/frame_0008.png",
+            "frames/frame_0009.png",
+            "frames/frame_0010.png",
+        ]);
+    }
+
+    #[test]
+    fn test_frame_order() {
+        let mut sequence = ImageSequence::numbered("#.png", 0..3);
+        assert_eq!(sequence.next_frame(), Some(0));
+
+        sequence.current = Some(2);
+        assert_eq!(sequence.next_frame(), None);
+        assert!(sequence.is_finished());
+
+        sequence.looping = true;
+        assert_eq!(sequence.next_frame(), Some(0));
+        assert_eq!(sequence.frame_after(2, 2), Some(1));
+        assert!(!sequence.is_finished());
+    }
+}
@@ -0,0 +1,329 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+    Condvar,
+    Mutex,
+    OnceLock,
+};
+use std::time::{Duration, Instant};
+
+use bevy::{
+    prelude::*,
+    utils::HashMap,
+};
+use ort::RunOptions;
+
+use crate::BevyOrtError;
+
+
+/// tracks `InferenceHandle` components, terminating their runs when the entity is despawned
+pub struct InferenceCancellationPlugin;
+impl Plugin for InferenceCancellationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ActiveInferences>();
+        app.add_systems(Last, cancel_removed_inferences);
+    }
+}
+
+
+/// controls `OrtSession::run_with_handle` calls, one at a time
+///
+/// attach the handle to the entity the request targets and the run is cancelled when the entity
+/// is despawned (or the component removed). the timeout applies to each run separately.
+#[derive(Component, Clone)]
+pub struct InferenceHandle {
+    run_options: Arc<RunOptions>,
+    timeout: Option<Duration>,
+    cancelled: Arc<AtomicBool>,
+    timed_out: Arc<AtomicBool>,
+    runs: Arc<AtomicU64>,
+    /// id of the run in progress, 0 when idle. held while resetting or terminating a run, so a
+    /// watch for a finished run can never terminate the next one
+    active_run: Arc<Mutex<u64>>,
+}
+
+impl InferenceHandle {
+    pub fn new() -> Result<Self, BevyOrtError> {
+        Ok(Self {
+            run_options: Arc::new(RunOptions::new()?),
+            timeout: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            timed_out: Arc::new(AtomicBool::new(false)),
+            runs: Arc::new(AtomicU64::new(0)),
+            active_run: Arc::new(Mutex::new(0)),
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn run_options(&self) -> Arc<RunOptions> {
+        self.run_options.clone()
+    }
+
+    /// terminates the run in progress, or makes the next run return `BevyOrtError::Cancelled`
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.run_options.terminate().ok();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// clears a pending cancellation, returning whether there was one
+    pub(crate) fn take_cancelled(&self) -> bool {
+        let cancelled = self.cancelled.swap(false, Ordering::SeqCst);
+        if cancelled {
+            self.run_options.unterminate().ok();
+        }
+
+        cancelled
+    }
+
+    /// whether the last run hit its timeout
+    pub fn is_timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::SeqCst)
+    }
+
+    fn is_running(&self, run: u64) -> bool {
+        *self.active_run.lock().unwrap() == run
+    }
+
+    fn time_out(&self, run: u64) {
+        let active_run = self.active_run.lock().unwrap();
+        if *active_run == run {
+            self.timed_out.store(true, Ordering::SeqCst);
+            self.run_options.terminate().ok();
+        }
+    }
+
+    /// resets the termination state and starts the run's deadline, the run ends when the guard is dropped
+    pub(crate) fn begin_run(&self) -> Result<RunGuard, BevyOrtError> {
+        if self.take_cancelled() {
+            return Err(BevyOrtError::Cancelled);
+        }
+
+        let run = self.runs.fetch_add(1, Ordering::SeqCst) + 1;
+        {
+            let mut active_run = self.active_run.lock().unwrap();
+
+            self.timed_out.store(false, Ordering::SeqCst);
+            self.run_options.unterminate().ok();
+
+            // a cancel racing the reset above must still terminate this run
+            if self.is_cancelled() {
+                self.run_options.terminate().ok();
+            }
+
+            *active_run = run;
+        }
+
+        // registered after releasing `active_run`, the watchdog locks it while holding its watches
+        if let Some(timeout) = self.timeout {
+            watchdog().watch(Instant::now() + timeout, run, self.clone());
+        }
+
+        Ok(RunGuard {
+            handle: self.clone(),
+        })
+    }
+
+    /// maps a failed run to `Timeout`/`Cancelled` when it was terminated by this handle
+    pub(crate) fn map_error(&self, error: ort::Error) -> BevyOrtError {
+        if self.is_timed_out() {
+            BevyOrtError::Timeout
+        } else if self.is_cancelled() {
+            BevyOrtError::Cancelled
+        } else {
+            BevyOrtError::Ort(error)
+        }
+    }
+}
+
+pub(crate) struct RunGuard {
+    handle: InferenceHandle,
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        let mut active_run = self.handle.active_run.lock().unwrap();
+        *active_run = 0;
+
+        // a cancel during the run is consumed by it
+        self.handle.take_cancelled();
+    }
+}
+
+
+struct Watch {
+    deadline: Instant,
+    run: u64,
+    handle: InferenceHandle,
+}
+
+/// one thread timing out every handle's runs
+struct Watchdog {
+    watches: Mutex<Vec<Watch>>,
+    wake: Condvar,
+}
+
+fn watchdog() -> &'static Watchdog {
+    static WATCHDOG: OnceLock<&'static Watchdog> = OnceLock::new();
+
+    WATCHDOG.get_or_init(|| {
+        let watchdog: &'static Watchdog = Box::leak(Box::new(Watchdog {
+            watches: Mutex::new(Vec::new()),
+            wake: Condvar::new(),
+        }));
+
+        std::thread::Builder::new()
+            .name("bevy_ort inference watchdog".to_string())
+            .spawn(move || watchdog.run())
+            .expect("failed to spawn inference watchdog");
+
+        watchdog
+    })
+}
+
+impl Watchdog {
+    fn watch(&self, deadline: Instant, run: u64, handle: InferenceHandle) {
+        self.watches.lock().unwrap().push(Watch {
+            deadline,
+            run,
+            handle,
+        });
+        self.wake.notify_one();
+    }
+
+    fn run(&self) {
+        let mut watches = self.watches.lock().unwrap();
+
+        loop {
+            let now = Instant::now();
+
+            watches.retain(|watch| {
+                let active = watch.handle.is_running(watch.run);
+                if active && watch.deadline <= now {
+                    watch.handle.time_out(watch.run);
+                    return false;
+                }
+
+                active
+            });
+
+            watches = match watches.iter().map(|watch| watch.deadline).min() {
+                Some(deadline) => self.wake.wait_timeout(watches, deadline - now).unwrap().0,
+                None => self.wake.wait(watches).unwrap(),
+            };
+        }
+    }
+}
+
+
+#[derive(Resource, Default)]
+pub struct ActiveInferences(HashMap<Entity, InferenceHandle>);
+
+
+fn cancel_removed_inferences(
+    mut active: ResMut<ActiveInferences>,
+    mut removed: RemovedComponents<InferenceHandle>,
+    changed: Query<
+        (
+            Entity,
+            &InferenceHandle,
+        ),
+        Changed<InferenceHandle>,
+    >,
+) {
+    for entity in removed.read() {
+        if let Some(handle) = active.0.remove(&entity) {
+            handle.cancel();
+        }
+    }
+
+    for (entity, handle) in changed.iter() {
+        active.0.insert(entity, handle.clone());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_short_circuits_next_run_once() {
+        let handle = InferenceHandle::new().unwrap();
+        handle.cancel();
+
+        assert!(matches!(handle.begin_run(), Err(BevyOrtError::Cancelled)), "cancelled handle should not start a run.");
+        assert!(handle.begin_run().is_ok(), "cancellation should only apply to one run.");
+    }
+
+    #[test]
+    fn test_cancel_during_run_is_consumed() {
+        let handle = InferenceHandle::new().unwrap();
+
+        let run = handle.begin_run().unwrap();
+        handle.cancel();
+        assert!(handle.is_cancelled(), "cancel should apply to the run in progress.");
+        drop(run);
+
+        assert!(!handle.is_cancelled(), "finished runs should consume the cancellation.");
+        assert!(handle.begin_run().is_ok(), "the next run should not be cancelled.");
+    }
+
+    #[test]
+    fn test_map_error() {
+        let handle = InferenceHandle::new().unwrap();
+        let error = || ort::Error::CustomError("failed".into());
+
+        assert!(matches!(handle.map_error(error()), BevyOrtError::Ort(_)), "unterminated runs should report the ort error.");
+
+        handle.cancel();
+        assert!(matches!(handle.map_error(error()), BevyOrtError::Cancelled), "cancelled runs should report cancellation.");
+
+        handle.timed_out.store(true, Ordering::SeqCst);
+        assert!(matches!(handle.map_error(error()), BevyOrtError::Timeout), "timed out runs should report the timeout.");
+    }
+
+    #[test]
+    fn test_stale_watch_does_not_time_out_next_run() {
+        let handle = InferenceHandle::new().unwrap();
+
+        drop(handle.begin_run().unwrap());
+        let run = handle.begin_run().unwrap();
+
+        handle.time_out(1);
+        assert!(!handle.is_timed_out(), "a watch for a finished run should not time out the next run.");
+
+        handle.time_out(2);
+        assert!(handle.is_timed_out(), "a watch for the active run should time it out.");
+        drop(run);
+    }
+
+    #[test]
+    fn test_watchdog_times_out_each_run() {
+        let handle = InferenceHandle::new().unwrap()
+            .with_timeout(Duration::from_millis(20));
+
+        let run = handle.begin_run().unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(handle.is_timed_out(), "a run past its timeout should be timed out.");
+        drop(run);
+
+        let run = handle.begin_run().unwrap();
+        assert!(!handle.is_timed_out(), "a new run should reset the timeout.");
+        drop(run);
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!handle.is_timed_out(), "finished runs should not time out.");
+    }
+}
@@ -1,25 +1,28 @@
-use std::io::ErrorKind;
-use std::sync::{Arc, Mutex};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
 use bevy::{
     prelude::*,
     asset::{
         AssetLoader,
+        AssetPath,
         AsyncReadExt,
         LoadContext,
-        io::Reader,
+        io::{
+            embedded::EMBEDDED,
+            Reader,
+        },
+    },
+    utils::{
+        BoxedFuture,
+        HashMap,
     },
-    utils::BoxedFuture,
-};
-use ort::{
-    CoreMLExecutionProvider,
-    CPUExecutionProvider,
-    DirectMLExecutionProvider,
-    CUDAExecutionProvider,
-    GraphOptimizationLevel,
-    OpenVINOExecutionProvider,
-    TensorRTExecutionProvider,
 };
+use flate2::read::GzDecoder;
+use ort::GraphOptimizationLevel;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 pub use ort::{
@@ -27,29 +30,137 @@ pub use ort::{
     Session,
 };
 
+pub mod batching;
+pub mod cache;
+pub mod execution_providers;
+pub mod image_sequence;
+pub mod inference;
 pub mod models;
+pub mod pipeline;
+pub mod policy;
+
+#[cfg(test)]
+mod test_utils;
+
+use execution_providers::ExecutionProviders;
 
 
 pub struct BevyOrtPlugin;
 impl Plugin for BevyOrtPlugin {
     fn build(&self, app: &mut App) {
-        // TODO: configurable execution providers via plugin settings
-        ort::init()
-            .with_execution_providers([
-                CoreMLExecutionProvider::default().build(),
-                CUDAExecutionProvider::default().build(),
-                OpenVINOExecutionProvider::default().build(),
-                DirectMLExecutionProvider::default().build(),
-                TensorRTExecutionProvider::default().build(),
-                CPUExecutionProvider::default().build(),
-            ])
-            .commit().ok();
+        let mut execution_providers = ExecutionProviders::default();
+        execution_providers.init_environment();
+        execution_providers.startup.log("bevy_ort");
+        app.insert_resource(execution_providers.clone());
+
+        let custom_operators = CustomOperators::default();
+        app.insert_resource(custom_operators.clone());
+
+        let embedded_onnx = EmbeddedOnnx::default();
+        app.insert_resource(embedded_onnx.clone());
+
+        app.add_plugins(inference::InferenceCancellationPlugin);
 
         app.init_asset::<Onnx>();
-        app.init_asset_loader::<OnnxLoader>();
+        app.register_asset_loader(OnnxLoader {
+            custom_operators,
+            embedded_onnx,
+            execution_providers,
+        });
+    }
+}
+
+
+#[derive(Clone)]
+pub enum CustomOperatorSource {
+    /// onnxruntime-extensions contrib operators
+    Extensions,
+    /// path to a custom operator shared library
+    Library(String),
+    /// builds a rust operator domain, called once per session
+    Domain(Arc<dyn Fn() -> Result<ort::OperatorDomain, ort::Error> + Send + Sync>),
+}
+
+/// custom operators registered with every session built by `OnnxLoader`
+///
+/// register operators before the models using them are loaded, e.g.
+/// `app.world.resource::<CustomOperators>().add_library("libcustom_ops.so")`
+#[derive(Resource, Clone, Default)]
+pub struct CustomOperators(Arc<RwLock<Vec<CustomOperatorSource>>>);
+
+impl CustomOperators {
+    pub fn add(&self, source: CustomOperatorSource) {
+        self.0.write().unwrap().push(source);
+    }
+
+    pub fn add_extensions(&self) {
+        self.add(CustomOperatorSource::Extensions);
+    }
+
+    pub fn add_library(&self, path: impl Into<String>) {
+        self.add(CustomOperatorSource::Library(path.into()));
+    }
+
+    pub fn add_domain(
+        &self,
+        domain: impl Fn() -> Result<ort::OperatorDomain, ort::Error> + Send + Sync + 'static,
+    ) {
+        self.add(CustomOperatorSource::Domain(Arc::new(domain)));
+    }
+
+    pub fn apply(&self, builder: ort::SessionBuilder) -> Result<ort::SessionBuilder, ort::Error> {
+        self.0.read().unwrap()
+            .iter()
+            .try_fold(builder, |builder, source| match source {
+                CustomOperatorSource::Extensions => builder.with_extensions(),
+                CustomOperatorSource::Library(path) => builder.with_operator_library(path),
+                CustomOperatorSource::Domain(domain) => builder.with_operators(domain()?),
+            })
+    }
+}
+
+
+/// static model bytes registered by `embedded_onnx!`, keyed by their `embedded://` asset path
+#[derive(Resource, Clone, Default)]
+pub struct EmbeddedOnnx(Arc<RwLock<HashMap<PathBuf, &'static [u8]>>>);
+
+impl EmbeddedOnnx {
+    pub fn insert(&self, path: impl Into<PathBuf>, bytes: &'static [u8]) {
+        self.0.write().unwrap().insert(path.into(), bytes);
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&'static [u8]> {
+        self.0.read().unwrap().get(path).copied()
     }
 }
 
+/// embeds an ONNX model into the binary and registers it with the `embedded://` asset source
+///
+/// mirrors `bevy::asset::embedded_asset!`, e.g. `embedded_onnx!(app, "models/modnet.onnx")` in
+/// `my_crate/src/lib.rs` can be loaded with `asset_server.load("embedded://my_crate/models/modnet.onnx")`.
+/// the loaded `Onnx` asset wraps an `InMemorySession` which borrows the static bytes directly.
+///
+/// must be called after `BevyOrtPlugin` has been added.
+#[macro_export]
+macro_rules! embedded_onnx {
+    ($app: ident, $path: expr) => {{
+        $crate::embedded_onnx!($app, "src", $path)
+    }};
+
+    ($app: ident, $source_path: expr, $path: expr) => {{
+        let bytes: &'static [u8] = include_bytes!($path);
+        let path = ::bevy::asset::embedded_path!($source_path, $path);
+        let watched_path = ::bevy::asset::io::embedded::watched_path(file!(), $path);
+
+        $app.world
+            .resource::<::bevy::asset::io::embedded::EmbeddedAssetRegistry>()
+            .insert_asset(watched_path, &path, bytes);
+        $app.world
+            .resource::<$crate::EmbeddedOnnx>()
+            .insert(path, bytes);
+    }};
+}
+
 
 pub enum OrtSession {
     Session(ort::Session),
@@ -67,6 +178,41 @@ impl OrtSession {
         }
     }
 
+    pub fn run_with_options<'s, 'i, 'v: 'i, const N: usize>(
+        &'s self,
+        input_values: impl Into<ort::SessionInputs<'i, 'v, N>>,
+        run_options: Arc<ort::RunOptions>,
+    ) -> Result<ort::SessionOutputs, ort::Error> {
+        match self {
+            OrtSession::Session(session) => session.run_with_options(input_values, run_options),
+            OrtSession::InMemory(session) => session.run_with_options(input_values, run_options),
+        }
+    }
+
+    /// runs with the handle's `RunOptions`, terminating at its timeout or when it is cancelled
+    pub fn run_with_handle<'s, 'i, 'v: 'i, const N: usize>(
+        &'s self,
+        input_values: impl Into<ort::SessionInputs<'i, 'v, N>>,
+        handle: &inference::InferenceHandle,
+    ) -> Result<ort::SessionOutputs, BevyOrtError> {
+        let _run = handle.begin_run()?;
+
+        self.run_with_options(input_values, handle.run_options())
+            .map_err(|e| handle.map_error(e))
+    }
+
+    /// runs with the handle when given, otherwise with default `RunOptions`
+    pub fn run_with<'s, 'i, 'v: 'i, const N: usize>(
+        &'s self,
+        input_values: impl Into<ort::SessionInputs<'i, 'v, N>>,
+        handle: Option<&inference::InferenceHandle>,
+    ) -> Result<ort::SessionOutputs, BevyOrtError> {
+        match handle {
+            Some(handle) => self.run_with_handle(input_values, handle),
+            None => Ok(self.run(input_values)?),
+        }
+    }
+
     pub fn inputs(&self) -> &Vec<ort::Input> {
         match self {
             OrtSession::Session(session) => &session.inputs,
@@ -80,12 +226,21 @@ impl OrtSession {
             OrtSession::InMemory(session) => &session.outputs,
         }
     }
+
+    pub fn metadata(&self) -> Result<ort::ModelMetadata, ort::Error> {
+        match self {
+            OrtSession::Session(session) => session.metadata(),
+            OrtSession::InMemory(session) => session.metadata(),
+        }
+    }
 }
 
 #[derive(Asset, Default, TypePath)]
 pub struct Onnx {
     pub session_data: Vec<u8>,
     pub session: Arc<Mutex<Option<OrtSession>>>,
+    /// hex encoded sha256 digest of the (decompressed) model bytes, unknown for sessions built elsewhere
+    pub sha256: Option<String>,
 }
 
 impl Onnx {
@@ -93,6 +248,7 @@ impl Onnx {
         Self {
             session_data: Vec::new(),
             session: Arc::new(Mutex::new(Some(OrtSession::Session(session)))),
+            sha256: None,
         }
     }
 
@@ -100,6 +256,7 @@ impl Onnx {
         Self {
             session_data: Vec::new(),
             session: Arc::new(Mutex::new(Some(OrtSession::InMemory(session)))),
+            sha256: None,
         }
     }
 }
@@ -111,41 +268,183 @@ pub enum BevyOrtError {
     Io(#[from] std::io::Error),
     #[error("ort error: {0}")]
     Ort(#[from] ort::Error),
+    #[error("inference timed out")]
+    Timeout,
+    #[error("inference cancelled")]
+    Cancelled,
+    #[error("missing model output: {0}")]
+    MissingOutput(String),
+    #[error("invalid model: {0}")]
+    InvalidModel(String),
+    #[error("sha256 mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OnnxLoaderSettings {
+    /// hex encoded sha256 digest of the asset file (compressed bytes for `.onnx.zst` and `.onnx.gz`)
+    pub sha256: Option<String>,
 }
 
 
 #[derive(Default)]
-pub struct OnnxLoader;
+pub struct OnnxLoader {
+    custom_operators: CustomOperators,
+    embedded_onnx: EmbeddedOnnx,
+    execution_providers: ExecutionProviders,
+}
+
+impl OnnxLoader {
+    fn session_builder(&self, path: &AssetPath) -> Result<ort::SessionBuilder, ort::Error> {
+        let (builder, report) = session_builder(&self.execution_providers, &self.custom_operators)?;
+        self.execution_providers.insert_session(path.clone_owned(), report);
+
+        Ok(builder)
+    }
+}
+
+/// session builder configured like `OnnxLoader` sessions, e.g. for sessions loaded outside the asset server
+// TODO: add session configuration
+pub fn session_builder(
+    execution_providers: &ExecutionProviders,
+    custom_operators: &CustomOperators,
+) -> Result<(ort::SessionBuilder, execution_providers::ExecutionProviderReport), ort::Error> {
+    let builder = Session::builder()?
+        .with_optimization_level(GraphOptimizationLevel::Level3)?;
+
+    // execution providers come from the environment, see `ExecutionProviders::init_environment`
+    let report = execution_providers.startup.clone();
+
+    Ok((custom_operators.apply(builder)?, report))
+}
+
 impl AssetLoader for OnnxLoader {
     type Asset = Onnx;
-    type Settings = ();
+    type Settings = OnnxLoaderSettings;
     type Error = BevyOrtError;
 
     fn load<'a>(
         &'a self,
         reader: &'a mut Reader,
-        _settings: &'a Self::Settings,
+        settings: &'a Self::Settings,
         load_context: &'a mut LoadContext,
     ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
         Box::pin(async move {
+            let extension = load_context.path()
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .unwrap_or_default()
+                .to_owned();
+
+            if extension == "onnx" && load_context.asset_path().source().as_str() == Some(EMBEDDED) {
+                if let Some(bytes) = self.embedded_onnx.get(load_context.path()) {
+                    verify_sha256(bytes, settings.sha256.as_deref())?;
+
+                    let session = self.session_builder(load_context.asset_path())?
+                        .commit_from_memory_directly(bytes)?;
+
+                    return Ok(Onnx {
+                        sha256: Some(format!("{:x}", Sha256::digest(bytes))),
+                        ..Onnx::from_in_memory(session)
+                    });
+                }
+            }
+
             let mut bytes = Vec::new();
             reader.read_to_end(&mut bytes).await.map_err(BevyOrtError::from)?;
 
-            match load_context.path().extension() {
-                Some(ext) if ext == "onnx" => {
-                    // TODO: add session configuration
-                    let session = Session::builder()?
-                        .with_optimization_level(GraphOptimizationLevel::Level3)?
-                        .commit_from_memory(&bytes)?;
+            verify_sha256(&bytes, settings.sha256.as_deref())?;
 
-                    Ok(Onnx::from_session(session))
-                },
-                _ => Err(BevyOrtError::Io(std::io::Error::new(ErrorKind::Other, "only .onnx supported"))),
-            }
+            let bytes = decompress(bytes, &extension)?;
+
+            let session = self.session_builder(load_context.asset_path())?
+                .commit_from_memory(&bytes)?;
+
+            Ok(Onnx {
+                sha256: Some(format!("{:x}", Sha256::digest(&bytes))),
+                session_data: bytes,
+                ..Onnx::from_session(session)
+            })
         })
     }
 
     fn extensions(&self) -> &[&str] {
-        &["onnx"]
+        &["onnx", "onnx.zst", "onnx.gz"]
+    }
+}
+
+
+fn decompress(bytes: Vec<u8>, extension: &str) -> Result<Vec<u8>, BevyOrtError> {
+    match extension {
+        "onnx" => Ok(bytes),
+        "zst" => Ok(zstd::stream::decode_all(bytes.as_slice())?),
+        "gz" => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        },
+        _ => Err(BevyOrtError::Io(std::io::Error::new(ErrorKind::Other, "only .onnx, .onnx.zst, and .onnx.gz supported"))),
+    }
+}
+
+fn verify_sha256(bytes: &[u8], expected: Option<&str>) -> Result<(), BevyOrtError> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+
+    let actual = format!("{:x}", Sha256::digest(bytes));
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(BevyOrtError::ChecksumMismatch {
+            expected: expected.to_owned(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const MODEL_BYTES: &[u8] = b"not really an onnx model";
+
+    #[test]
+    fn test_decompress_zst() {
+        let compressed = zstd::stream::encode_all(MODEL_BYTES, 0).unwrap();
+        assert_eq!(decompress(compressed, "zst").unwrap(), MODEL_BYTES);
+    }
+
+    #[test]
+    fn test_decompress_gz() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(MODEL_BYTES).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(decompress(compressed, "gz").unwrap(), MODEL_BYTES);
+    }
+
+    #[test]
+    fn test_verify_sha256() {
+        let digest = format!("{:x}", Sha256::digest(MODEL_BYTES));
+
+        assert!(verify_sha256(MODEL_BYTES, None).is_ok());
+        assert!(verify_sha256(MODEL_BYTES, Some(&digest)).is_ok());
+        assert!(verify_sha256(MODEL_BYTES, Some(&digest.to_uppercase())).is_ok());
+
+        let truncated = &MODEL_BYTES[..MODEL_BYTES.len() - 1];
+        assert!(
+            matches!(verify_sha256(truncated, Some(&digest)), Err(BevyOrtError::ChecksumMismatch { .. })),
+            "a truncated model should fail checksum verification.",
+        );
     }
 }
@@ -1,5 +1,6 @@
 use bevy::{
     prelude::*,
+    core::FrameCount,
     render::{
         mesh::{
             Indices,
@@ -16,9 +17,22 @@ use ndarray::Array2;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    cache::{
+        CacheKey,
+        CacheKeyBuilder,
+        InferenceCache,
+    },
+    inference::InferenceHandle,
     inputs,
+    BevyOrtError,
     Onnx,
     OrtSession,
+    policy::{
+        InferencePolicy,
+        InferencePolicyPlugin,
+        InferencePolicySet,
+        InferenceStaleness,
+    },
 };
 
 
@@ -28,8 +42,10 @@ pub static INDEX_BUFFER: &[u8] = include_bytes_aligned!(4, "flame_index_buffer.b
 pub struct FlamePlugin;
 impl Plugin for FlamePlugin {
     fn build(&self, app: &mut App) {
+        InferencePolicyPlugin::add_once(app);
+
         app.init_resource::<Flame>();
-        app.add_systems(PreUpdate, flame_inference_system);
+        app.add_systems(PreUpdate, flame_inference_system.after(InferencePolicySet));
     }
 }
 
@@ -45,30 +61,63 @@ fn flame_inference_system(
     mut commands: Commands,
     flame: Res<Flame>,
     onnx_assets: Res<Assets<Onnx>>,
-    flame_inputs: Query<
+    asset_server: Res<AssetServer>,
+    cache: Option<Res<InferenceCache>>,
+    frame_count: Res<FrameCount>,
+    time: Res<Time>,
+    mut flame_inputs: Query<
         (
             Entity,
-            &FlameInput,
+            Ref<FlameInput>,
+            Option<&InferencePolicy>,
+            Option<&mut InferenceStaleness>,
+            Has<FlameOutput>,
+            Option<&InferenceHandle>,
         ),
-        Without<FlameOutput>,
     >,
 ) {
-    for (entity, flame_input) in flame_inputs.iter() {
-        let flame_output: Result<FlameOutput, String> = (|| {
-            let onnx = onnx_assets.get(&flame.onnx).ok_or("failed to get flame ONNX asset")?;
-            let session_lock = onnx.session.lock().map_err(|e| e.to_string())?;
-            let session = session_lock.as_ref().ok_or("failed to get flame session from flame ONNX asset")?;
-
-            Ok(flame_inference(
-                session,
-                flame_input,
-            ))
-        })();
+    for (entity, flame_input, policy, mut staleness, has_output, handle) in flame_inputs.iter_mut() {
+        let should_run = match policy {
+            Some(policy) => policy.should_run(staleness.as_deref(), flame_input.is_changed()),
+            None => !has_output,
+        };
+
+        if !should_run {
+            continue;
+        }
+
+        // the model is still loading
+        let Some(onnx) = onnx_assets.get(&flame.onnx) else {
+            return;
+        };
+        let Ok(session_lock) = onnx.session.lock() else {
+            return;
+        };
+        let Some(session) = session_lock.as_ref() else {
+            return;
+        };
+
+        let flame_output = match cache.as_ref() {
+            Some(cache) => {
+                let model = CacheKey::model(&asset_server, &flame.onnx, onnx);
+                flame_inference_cached_with_handle(cache, model, session, &flame_input, handle)
+            },
+            None => flame_inference_with_handle(session, &flame_input, handle),
+        };
 
         match flame_output {
             Ok(flame_output) => {
                 commands.entity(entity)
                     .insert(flame_output);
+
+                match staleness.as_mut() {
+                    Some(staleness) => staleness.mark_run(frame_count.0, time.elapsed()),
+                    None if policy.is_some() => {
+                        commands.entity(entity)
+                            .insert(InferenceStaleness::ran(frame_count.0, time.elapsed()));
+                    },
+                    None => {},
+                }
             }
             Err(_e) => {
                 return;
@@ -78,6 +127,51 @@ fn flame_inference_system(
 }
 
 
+/// `flame_inference` through `cache`, `model` is e.g. `CacheKey::model`
+pub fn flame_inference_cached(
+    cache: &InferenceCache,
+    model: CacheKeyBuilder,
+    session: &OrtSession,
+    input: &FlameInput,
+) -> FlameOutput {
+    flame_inference_cached_with_handle(cache, model, session, input, None).unwrap()
+}
+
+/// `flame_inference_cached` with the session run controlled by `handle`
+pub fn flame_inference_cached_with_handle(
+    cache: &InferenceCache,
+    model: CacheKeyBuilder,
+    session: &OrtSession,
+    input: &FlameInput,
+    handle: Option<&InferenceHandle>,
+) -> Result<FlameOutput, BevyOrtError> {
+    cache.try_get_or_run_persistent(
+        flame_cache_key(model, input),
+        || flame_inference_with_handle(session, input, handle),
+    )
+}
+
+/// key of `flame_inference` results for `input`, `model` is e.g. `CacheKey::model`
+pub fn flame_cache_key(
+    model: CacheKeyBuilder,
+    input: &FlameInput,
+) -> CacheKey {
+    let PreparedInput {
+        shape,
+        pose,
+        expression,
+        neck,
+        eye,
+    } = prepare_input(input);
+
+    [shape, pose, expression, neck, eye].iter()
+        .fold(model, |key, array| {
+            key.tensor(array.shape(), array.as_slice().unwrap())
+        })
+        .finish()
+}
+
+
 const FLAME_BATCH_SIZE: usize = 1;
 
 #[derive(
@@ -149,6 +243,15 @@ pub fn flame_inference(
     session: &OrtSession,
     input: &FlameInput,
 ) -> FlameOutput {
+    flame_inference_with_handle(session, input, None).unwrap()
+}
+
+/// `flame_inference` with the session run controlled by `handle`
+pub fn flame_inference_with_handle(
+    session: &OrtSession,
+    input: &FlameInput,
+    handle: Option<&InferenceHandle>,
+) -> Result<FlameOutput, BevyOrtError> {
     let PreparedInput {
         shape,
         expression,
@@ -163,17 +266,17 @@ pub fn flame_inference(
         "pose" => pose.view(),
         "neck" => neck.view(),
         "eye" => eye.view(),
-    ].map_err(|e| e.to_string()).unwrap();
-    let outputs = session.run(input_values).map_err(|e| e.to_string());
-    let binding = outputs.ok().unwrap();
+    ]?;
+    let outputs = session.run_with(input_values, handle)?;
 
-    let vertices: &ort::Value = binding.get("vertices").unwrap();
-    // let landmarks: &ort::Value = binding.get("landmarks").unwrap();
+    let vertices: &ort::Value = outputs.get("vertices")
+        .ok_or_else(|| BevyOrtError::MissingOutput("vertices".to_string()))?;
+    // let landmarks: &ort::Value = outputs.get("landmarks").unwrap();
 
-    post_process(
+    Ok(post_process(
         vertices,
         // landmarks,
-    )
+    ))
 }
 
 
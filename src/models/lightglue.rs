@@ -4,7 +4,14 @@ use ndarray::{Array, ArrayD, Axis};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    cache::{
+        CacheKey,
+        CacheKeyBuilder,
+        InferenceCache,
+    },
+    inference::InferenceHandle,
     inputs,
+    BevyOrtError,
     Onnx,
     OrtSession,
 };
@@ -33,10 +40,43 @@ pub struct GluedPair {
 }
 
 
+/// `lightglue_inference` through `cache`, `model` is e.g. `CacheKey::model`
+pub fn lightglue_inference_cached(
+    cache: &InferenceCache,
+    model: CacheKeyBuilder,
+    session: &OrtSession,
+    images: &[&Image],
+) -> Vec<(usize, usize, Vec<GluedPair>)> {
+    cache.get_or_run_persistent(
+        lightglue_cache_key(model, images),
+        || lightglue_inference(session, images),
+    )
+}
+
+/// key of `lightglue_inference` results for `images`, `model` is e.g. `CacheKey::model`
+pub fn lightglue_cache_key(
+    model: CacheKeyBuilder,
+    images: &[&Image],
+) -> CacheKey {
+    images.iter()
+        .fold(model, |key, image| key.image(image))
+        .finish()
+}
+
+
 pub fn lightglue_inference(
     session: &OrtSession,
     images: &[&Image],
 ) -> Vec<(usize, usize, Vec<GluedPair>)> {
+    lightglue_inference_with_handle(session, images, None).unwrap()
+}
+
+/// `lightglue_inference` with each session run controlled by `handle`
+pub fn lightglue_inference_with_handle(
+    session: &OrtSession,
+    images: &[&Image],
+    handle: Option<&InferenceHandle>,
+) -> Result<Vec<(usize, usize, Vec<GluedPair>)>, BevyOrtError> {
     let unique_unordered_pairs = images.iter().enumerate()
         .flat_map(|(i, _)| {
             images.iter().enumerate().skip(i + 1).map(move |(j, _)| (i, j))
@@ -54,25 +94,24 @@ pub fn lightglue_inference(
             let input_values = inputs![
                 "image0" => prepared_a.view(),
                 "image1" => prepared_b.view(),
-            ].map_err(|e| e.to_string()).unwrap();
-            let outputs = session.run(input_values).map_err(|e| e.to_string());
-            let binding = outputs.ok().unwrap();
-
-            let kpts0: &ort::Value = binding.get("kpts0").unwrap();
-            let kpts1: &ort::Value = binding.get("kpts1").unwrap();
-            let matches0: &ort::Value = binding.get("matches0").unwrap();
-
-            (
-                *i,
-                *j,
-                post_process(
-                    kpts0,
-                    kpts1,
-                    matches0,
-                ).unwrap(),
-            )
+            ]?;
+            let outputs = session.run_with(input_values, handle)?;
+
+            let output = |name: &str| outputs.get(name)
+                .ok_or_else(|| BevyOrtError::MissingOutput(name.to_string()));
+            let kpts0: &ort::Value = output("kpts0")?;
+            let kpts1: &ort::Value = output("kpts1")?;
+            let matches0: &ort::Value = output("matches0")?;
+
+            let pairs = post_process(
+                kpts0,
+                kpts1,
+                matches0,
+            ).map_err(|e| BevyOrtError::InvalidModel(e.to_string()))?;
+
+            Ok((*i, *j, pairs))
         })
-        .collect::<Vec<_>>()
+        .collect()
 }
 
 
@@ -1,5 +1,6 @@
 use bevy::{
     prelude::*,
+    core::FrameCount,
     render::{
         render_asset::RenderAssetUsages,
         render_resource::{
@@ -9,14 +10,29 @@ use bevy::{
         },
     },
 };
-use image::{DynamicImage, GenericImageView, imageops::FilterType, ImageBuffer, Luma, RgbImage};
-use ndarray::{Array, Array4, ArrayView4};
+use image::{imageops::FilterType, ImageBuffer, Luma, RgbImage, RgbaImage};
+use half::f16;
+use ndarray::{s, Array, Array2, Array3, Array4, ArrayView4};
 use rayon::prelude::*;
 
 use crate::{
+    batching::BatchedModel,
+    cache::{
+        CacheKey,
+        CacheKeyBuilder,
+        InferenceCache,
+    },
+    inference::InferenceHandle,
     inputs,
+    BevyOrtError,
     Onnx,
     OrtSession,
+    policy::{
+        InferencePolicy,
+        InferencePolicyPlugin,
+        InferencePolicySet,
+        InferenceStaleness,
+    },
 };
 
 
@@ -24,13 +40,312 @@ use crate::{
 pub struct ModnetPlugin;
 impl Plugin for ModnetPlugin {
     fn build(&self, app: &mut App) {
+        InferencePolicyPlugin::add_once(app);
+
         app.init_resource::<Modnet>();
+        app.register_type::<ModnetInput>();
+        app.register_type::<ModnetOutput>();
+        app.add_systems(PreUpdate, modnet_inference_system.after(InferencePolicySet));
+        app.add_systems(PostUpdate, modnet_composite_system);
     }
 }
 
 #[derive(Resource, Default)]
 pub struct Modnet {
     pub onnx: Handle<Onnx>,
+    pub config: ModnetConfig,
+}
+
+
+/// matted whenever it changes, or as the entity's `InferencePolicy` schedules
+#[derive(Debug, Clone, Default, Component, Reflect)]
+pub struct ModnetInput {
+    pub image: Handle<Image>,
+}
+
+/// matte of the entity's `ModnetInput`, updated in place on later runs
+#[derive(Debug, Clone, Default, Component, Reflect)]
+pub struct ModnetOutput {
+    pub matte: Handle<Image>,
+}
+
+
+fn modnet_inference_system(
+    mut commands: Commands,
+    modnet: Res<Modnet>,
+    onnx_assets: Res<Assets<Onnx>>,
+    asset_server: Res<AssetServer>,
+    cache: Option<Res<InferenceCache>>,
+    mut images: ResMut<Assets<Image>>,
+    frame_count: Res<FrameCount>,
+    time: Res<Time>,
+    mut modnet_inputs: Query<
+        (
+            Entity,
+            Ref<ModnetInput>,
+            Option<&InferencePolicy>,
+            Option<&mut InferenceStaleness>,
+            Option<&ModnetOutput>,
+            Option<&mut ModnetTemporalFilter>,
+            Option<&InferenceHandle>,
+        ),
+    >,
+) {
+    for (entity, modnet_input, policy, mut staleness, modnet_output, temporal_filter, handle) in modnet_inputs.iter_mut() {
+        let should_run = match policy {
+            Some(policy) => policy.should_run(staleness.as_deref(), modnet_input.is_changed()),
+            None => modnet_output.is_none() || modnet_input.is_changed(),
+        };
+
+        if !should_run {
+            continue;
+        }
+
+        let Some(image) = images.get(&modnet_input.image) else {
+            continue;
+        };
+
+        // the model is still loading
+        let Some(onnx) = onnx_assets.get(&modnet.onnx) else {
+            continue;
+        };
+        let Ok(session_lock) = onnx.session.lock() else {
+            continue;
+        };
+        let Some(session) = session_lock.as_ref() else {
+            continue;
+        };
+
+        let matte = match cache.as_ref() {
+            Some(cache) => {
+                let model = CacheKey::model(&asset_server, &modnet.onnx, onnx);
+                modnet_inference_cached_with_handle(cache, model, session, image, &modnet.config, handle)
+            },
+            None => single_matte(session, image, &modnet.config, handle),
+        };
+
+        let matte = match matte {
+            Ok(matte) => matte,
+            Err(e) => {
+                warn!("modnet inference failed for {:?}: {}", entity, e);
+                continue;
+            }
+        };
+
+        let matte = match temporal_filter {
+            Some(mut temporal_filter) => temporal_filter.apply(&matte),
+            None => matte,
+        };
+
+        match modnet_output {
+            Some(modnet_output) => {
+                images.insert(&modnet_output.matte, matte);
+            },
+            None => {
+                commands.entity(entity)
+                    .insert(ModnetOutput {
+                        matte: images.add(matte),
+                    });
+            },
+        }
+
+        match staleness.as_mut() {
+            Some(staleness) => staleness.mark_run(frame_count.0, time.elapsed()),
+            None if policy.is_some() => {
+                commands.entity(entity)
+                    .insert(InferenceStaleness::ran(frame_count.0, time.elapsed()));
+            },
+            None => {},
+        }
+    }
+}
+
+
+/// per-channel input normalization, applied to rgb values scaled to 0.0..=1.0
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ModnetNormalization {
+    /// `(v - 0.5) / 0.5`, used by the original MODNet checkpoints
+    #[default]
+    Symmetric,
+    /// ImageNet mean and standard deviation
+    ImageNet,
+    Custom {
+        mean: [f32; 3],
+        std: [f32; 3],
+    },
+}
+
+impl ModnetNormalization {
+    pub fn mean_std(&self) -> ([f32; 3], [f32; 3]) {
+        match *self {
+            ModnetNormalization::Symmetric => ([0.5; 3], [0.5; 3]),
+            ModnetNormalization::ImageNet => ([0.485, 0.456, 0.406], [0.229, 0.224, 0.225]),
+            ModnetNormalization::Custom { mean, std } => (mean, std),
+        }
+    }
+}
+
+/// preprocessing parameters for MODNet and compatible matting models
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModnetConfig {
+    /// images smaller than this on their longest side are upscaled towards it
+    pub ref_size: u32,
+    /// inference width and height are rounded down to a multiple of this
+    pub alignment: u32,
+    pub normalization: ModnetNormalization,
+    pub resize_filter: FilterType,
+    pub matte_format: MatteFormat,
+    /// run overlapping full resolution tiles instead of a single downscaled pass
+    pub tiling: Option<ModnetTiling>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModnetTiling {
+    /// tile width and height, rounded down to the config alignment
+    pub tile_size: u32,
+    /// pixels shared by neighbouring tiles, blended with feathered weights
+    pub overlap: u32,
+    /// weight of the low resolution global pass in the final matte
+    pub global_weight: f32,
+    /// tiles per session run
+    pub batch_size: usize,
+}
+
+impl Default for ModnetTiling {
+    fn default() -> Self {
+        Self {
+            tile_size: 512,
+            overlap: 64,
+            global_weight: 0.25,
+            batch_size: 4,
+        }
+    }
+}
+
+impl Default for ModnetConfig {
+    fn default() -> Self {
+        Self {
+            ref_size: 512,
+            alignment: 32,
+            normalization: ModnetNormalization::Symmetric,
+            resize_filter: FilterType::Triangle,
+            matte_format: MatteFormat::R8Unorm,
+            tiling: None,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum CompositeBackground {
+    #[default]
+    Transparent,
+    Color(Color),
+    Image(Handle<Image>),
+}
+
+/// keeps `output` up to date with `source` composited over `background` using `matte`
+///
+/// a default `output` handle is replaced with a newly added image on first composite.
+#[derive(Component, Debug, Clone, Default)]
+pub struct ModnetComposite {
+    pub source: Handle<Image>,
+    pub matte: Handle<Image>,
+    pub background: CompositeBackground,
+    pub output: Handle<Image>,
+}
+
+fn modnet_composite_system(
+    mut composites: Query<&mut ModnetComposite>,
+    mut image_events: EventReader<AssetEvent<Image>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let modified = image_events.read()
+        .filter_map(|event| match event {
+            AssetEvent::Added { id }
+            | AssetEvent::Modified { id }
+            | AssetEvent::LoadedWithDependencies { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    for mut composite in composites.iter_mut() {
+        let background_id = match &composite.background {
+            CompositeBackground::Image(handle) => Some(handle.id()),
+            _ => None,
+        };
+
+        let dirty = composite.is_changed() || modified.iter().any(|id| {
+            *id == composite.source.id()
+                || *id == composite.matte.id()
+                || Some(*id) == background_id
+        });
+        if !dirty {
+            continue;
+        }
+
+        let result = {
+            let source = images.get(&composite.source);
+            let matte = images.get(&composite.matte);
+
+            match (source, matte, &composite.background) {
+                (Some(source), Some(matte), CompositeBackground::Transparent) => {
+                    Some(modnet_cutout(source, matte))
+                },
+                (Some(source), Some(matte), CompositeBackground::Color(color)) => {
+                    Some(modnet_composite(source, matte, Background::Color(*color)))
+                },
+                (Some(source), Some(matte), CompositeBackground::Image(background)) => {
+                    images.get(background)
+                        .map(|background| modnet_composite(source, matte, Background::Image(background)))
+                },
+                _ => None,
+            }
+        };
+
+        let Some(result) = result else {
+            continue;
+        };
+
+        if composite.output == Handle::default() {
+            // avoid re-triggering `Changed<ModnetComposite>` next frame
+            composite.bypass_change_detection().output = images.add(result);
+        } else {
+            images.insert(&composite.output, result);
+        }
+    }
+}
+
+impl BatchedModel for Modnet {
+    type Input = Handle<Image>;
+    type Output = Handle<Image>;
+
+    fn onnx(&self) -> &Handle<Onnx> {
+        &self.onnx
+    }
+
+    fn ready(&self, input: &Handle<Image>, images: &Assets<Image>) -> bool {
+        images.contains(input)
+    }
+
+    fn infer(
+        &self,
+        session: &OrtSession,
+        inputs: &[&Handle<Image>],
+        images: &mut Assets<Image>,
+        handle: Option<&InferenceHandle>,
+    ) -> Result<Vec<Handle<Image>>, String> {
+        let masks = {
+            let sources = inputs.iter()
+                .map(|&handle| images.get(handle).ok_or("failed to get modnet input image"))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            modnet_inference_with_handle(session, &sources, None, &self.config, handle)
+                .map_err(|e| e.to_string())?
+        };
+
+        Ok(masks.into_iter().map(|mask| images.add(mask)).collect())
+    }
 }
 
 
@@ -38,22 +353,420 @@ pub fn modnet_inference(
     session: &OrtSession,
     images: &[&Image],
     max_size: Option<(u32, u32)>,
-) -> Vec<Image> {
-    let input = images_to_modnet_input(images, max_size);
+    config: &ModnetConfig,
+) -> Result<Vec<Image>, BevyOrtError> {
+    modnet_inference_with_handle(session, images, max_size, config, None)
+}
+
+/// `modnet_inference` with each session run controlled by `handle`
+pub fn modnet_inference_with_handle(
+    session: &OrtSession,
+    images: &[&Image],
+    max_size: Option<(u32, u32)>,
+    config: &ModnetConfig,
+    handle: Option<&InferenceHandle>,
+) -> Result<Vec<Image>, BevyOrtError> {
+    let mattes = inference_mattes(session, images, max_size, config, MatteSize::Inference, BatchLayout::Group, handle)?;
+
+    Ok(mattes.into_iter().map(|matte| matte.image).collect())
+}
+
+
+/// `modnet_inference` of a single image through `cache`, `model` is e.g. `CacheKey::model`
+///
+/// mattes are images and are only kept in memory, they are never written to the persistence directory.
+pub fn modnet_inference_cached(
+    cache: &InferenceCache,
+    model: CacheKeyBuilder,
+    session: &OrtSession,
+    image: &Image,
+    config: &ModnetConfig,
+) -> Result<Image, BevyOrtError> {
+    modnet_inference_cached_with_handle(cache, model, session, image, config, None)
+}
+
+/// `modnet_inference_cached` with each session run controlled by `handle`
+pub fn modnet_inference_cached_with_handle(
+    cache: &InferenceCache,
+    model: CacheKeyBuilder,
+    session: &OrtSession,
+    image: &Image,
+    config: &ModnetConfig,
+    handle: Option<&InferenceHandle>,
+) -> Result<Image, BevyOrtError> {
+    cache.try_get_or_run(
+        modnet_cache_key(model, image, config),
+        || single_matte(session, image, config, handle),
+    )
+}
 
-    let input_values = inputs!["input" => input.view()].map_err(|e| e.to_string()).unwrap();
-    let outputs = session.run(input_values).map_err(|e| e.to_string());
-    let binding = outputs.ok().unwrap();
-    let output_value: &ort::Value = binding.get("output").unwrap();
+fn single_matte(
+    session: &OrtSession,
+    image: &Image,
+    config: &ModnetConfig,
+    handle: Option<&InferenceHandle>,
+) -> Result<Image, BevyOrtError> {
+    modnet_inference_with_handle(session, &[image], None, config, handle)?
+        .pop()
+        .ok_or_else(|| BevyOrtError::MissingOutput("modnet returned no matte".to_string()))
+}
 
-    modnet_output_to_luma_images(output_value)
+/// key of `modnet_inference` results for `image`, `model` is e.g. `CacheKey::model`
+pub fn modnet_cache_key(
+    model: CacheKeyBuilder,
+    image: &Image,
+    config: &ModnetConfig,
+) -> CacheKey {
+    model
+        .debug(config)
+        .image(image)
+        .finish()
+}
+
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MatteSize {
+    /// the resized, aligned resolution the model ran at
+    #[default]
+    Inference,
+    /// each input image's original resolution, resampled with the given filter
+    Original(FilterType),
+}
+
+/// how images with different target shapes share a batch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BatchLayout {
+    /// one session run per distinct target shape
+    #[default]
+    Group,
+    /// pad every image to the largest target shape and run once, cropping each matte back
+    Pad,
+}
+
+/// texture format of output matte images
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatteFormat {
+    #[default]
+    R8Unorm,
+    R16Unorm,
+    R16Float,
+    R32Float,
+}
+
+impl MatteFormat {
+    pub fn texture_format(&self) -> TextureFormat {
+        match self {
+            MatteFormat::R8Unorm => TextureFormat::R8Unorm,
+            MatteFormat::R16Unorm => TextureFormat::R16Unorm,
+            MatteFormat::R16Float => TextureFormat::R16Float,
+            MatteFormat::R32Float => TextureFormat::R32Float,
+        }
+    }
+
+    pub fn from_texture_format(format: TextureFormat) -> Option<Self> {
+        match format {
+            TextureFormat::R8Unorm => Some(MatteFormat::R8Unorm),
+            TextureFormat::R16Unorm => Some(MatteFormat::R16Unorm),
+            TextureFormat::R16Float => Some(MatteFormat::R16Float),
+            TextureFormat::R32Float => Some(MatteFormat::R32Float),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModnetMatte {
+    pub image: Image,
+    /// full precision (height, width) matte matching `image`
+    pub matte: Array2<f32>,
+    /// scale from the original image to the inference resolution
+    pub scale: Vec2,
+    pub original_size: UVec2,
+    pub inference_size: UVec2,
+}
+
+pub fn modnet_inference_mattes(
+    session: &OrtSession,
+    images: &[&Image],
+    max_size: Option<(u32, u32)>,
+    config: &ModnetConfig,
+    size: MatteSize,
+    layout: BatchLayout,
+) -> Result<Vec<ModnetMatte>, BevyOrtError> {
+    inference_mattes(session, images, max_size, config, size, layout, None)
+}
+
+fn inference_mattes(
+    session: &OrtSession,
+    images: &[&Image],
+    max_size: Option<(u32, u32)>,
+    config: &ModnetConfig,
+    size: MatteSize,
+    layout: BatchLayout,
+    handle: Option<&InferenceHandle>,
+) -> Result<Vec<ModnetMatte>, BevyOrtError> {
+    // tiled mattes are always at the original resolution
+    if let Some(tiling) = config.tiling {
+        return images.iter()
+            .map(|&image| {
+                let matte = tiled_matte(session, image, config, &tiling, handle)?;
+                let original_size = UVec2::new(image.width(), image.height());
+
+                Ok(ModnetMatte {
+                    image: matte_to_image(&matte, config.matte_format),
+                    matte: matte_to_array(matte),
+                    scale: Vec2::ONE,
+                    original_size,
+                    inference_size: original_size,
+                })
+            })
+            .collect();
+    }
+
+    let prepared = prepare_images(images, max_size, config);
+
+    let mattes = match layout {
+        BatchLayout::Group => {
+            let mut groups: Vec<((usize, usize), Vec<usize>)> = Vec::new();
+            for (i, image) in prepared.iter().enumerate() {
+                match groups.iter_mut().find(|(shape, _)| *shape == image.shape()) {
+                    Some((_, indices)) => indices.push(i),
+                    None => groups.push((image.shape(), vec![i])),
+                }
+            }
+
+            let mut mattes: Vec<Option<Matte>> = vec![None; prepared.len()];
+            for (shape, indices) in groups {
+                let group = indices.iter().map(|&i| &prepared[i]).collect::<Vec<_>>();
+                let input = stack_images(&group, shape);
+
+                for (i, matte) in indices.into_iter().zip(run_modnet(session, &input, handle)?) {
+                    mattes[i] = Some(matte);
+                }
+            }
+
+            mattes.into_iter()
+                .map(|matte| matte.ok_or_else(|| BevyOrtError::MissingOutput("modnet output batch size mismatch".to_string())))
+                .collect::<Result<Vec<_>, _>>()?
+        },
+        BatchLayout::Pad => {
+            let input = stack_images(&prepared.iter().collect::<Vec<_>>(), padded_shape(&prepared));
+
+            run_modnet(session, &input, handle)?
+                .into_iter()
+                .zip(prepared.iter())
+                .map(|(matte, image)| {
+                    let (height, width) = image.shape();
+                    image::imageops::crop_imm(&matte, 0, 0, width as u32, height as u32).to_image()
+                })
+                .collect::<Vec<_>>()
+        },
+    };
+
+    let mattes = mattes
+        .into_par_iter()
+        .zip(images.par_iter())
+        .zip(prepared.par_iter())
+        .map(|((matte, &image), prepared)| {
+            let inference_size = UVec2::new(matte.width(), matte.height());
+            let original_size = UVec2::new(image.width(), image.height());
+
+            let matte = match size {
+                MatteSize::Inference => matte,
+                MatteSize::Original(filter) if inference_size != original_size => {
+                    image::imageops::resize(&matte, original_size.x, original_size.y, filter)
+                },
+                MatteSize::Original(_) => matte,
+            };
+
+            ModnetMatte {
+                image: matte_to_image(&matte, config.matte_format),
+                matte: matte_to_array(matte),
+                scale: prepared.scale,
+                original_size,
+                inference_size,
+            }
+        })
+        .collect();
+
+    Ok(mattes)
+}
+
+/// full resolution matte from overlapping `tiling.tile_size` tiles blended with a low resolution global pass
+pub fn modnet_inference_tiled(
+    session: &OrtSession,
+    image: &Image,
+    config: &ModnetConfig,
+    tiling: &ModnetTiling,
+) -> Result<Image, BevyOrtError> {
+    Ok(matte_to_image(&tiled_matte(session, image, config, tiling, None)?, config.matte_format))
+}
+
+fn tiled_matte(
+    session: &OrtSession,
+    image: &Image,
+    config: &ModnetConfig,
+    tiling: &ModnetTiling,
+    handle: Option<&InferenceHandle>,
+) -> Result<Matte, BevyOrtError> {
+    let (width, height) = (image.width(), image.height());
+
+    let global = {
+        let prepared = prepare_images(&[image], Some((config.ref_size, config.ref_size)), config);
+        let input = stack_images(&prepared.iter().collect::<Vec<_>>(), padded_shape(&prepared));
+        let matte = run_modnet(session, &input, handle)?
+            .pop()
+            .ok_or_else(|| BevyOrtError::MissingOutput("modnet returned no matte".to_string()))?;
+
+        image::imageops::resize(&matte, width, height, FilterType::Triangle)
+    };
+
+    let alignment = config.alignment.max(1);
+    let tile_width = tiling.tile_size.min(width) / alignment * alignment;
+    let tile_height = tiling.tile_size.min(height) / alignment * alignment;
+    if tile_width == 0 || tile_height == 0 {
+        return Ok(global);
+    }
+
+    let rgb = image.clone()
+        .try_into_dynamic()
+        .unwrap()
+        .to_rgb8();
+
+    let tiles = tile_offsets(height, tile_height, tiling.overlap)
+        .into_iter()
+        .flat_map(|y| {
+            tile_offsets(width, tile_width, tiling.overlap)
+                .into_iter()
+                .map(move |x| (x, y))
+        })
+        .collect::<Vec<_>>();
+
+    let mut value = vec![0.0; (width * height) as usize];
+    let mut weight = vec![0.0; (width * height) as usize];
+
+    for chunk in tiles.chunks(tiling.batch_size.max(1)) {
+        let prepared = chunk.par_iter()
+            .map(|&(x, y)| {
+                let tile = image::imageops::crop_imm(&rgb, x, y, tile_width, tile_height).to_image();
+
+                PreparedImage {
+                    tensor: image_to_ndarray(&tile, config.normalization),
+                    scale: Vec2::ONE,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let input = stack_images(&prepared.iter().collect::<Vec<_>>(), (tile_height as usize, tile_width as usize));
+
+        for (&(x, y), matte) in chunk.iter().zip(run_modnet(session, &input, handle)?) {
+            for (tx, ty, pixel) in matte.enumerate_pixels() {
+                let feather = tile_feather(tx, x, tile_width, width, tiling.overlap)
+                    * tile_feather(ty, y, tile_height, height, tiling.overlap);
+
+                let i = ((y + ty) * width + x + tx) as usize;
+                value[i] += pixel.0[0] * feather;
+                weight[i] += feather;
+            }
+        }
+    }
+
+    let global_weight = tiling.global_weight.clamp(0.0, 1.0);
+    let data = global.as_raw()
+        .iter()
+        .zip(value.iter().zip(weight.iter()))
+        .map(|(&global, (&value, &weight))| {
+            if weight > 0.0 {
+                global * global_weight + value / weight * (1.0 - global_weight)
+            } else {
+                global
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Matte::from_raw(width, height, data).unwrap())
+}
+
+/// tile start offsets covering `length`, the last tile is aligned to the end
+fn tile_offsets(length: u32, tile: u32, overlap: u32) -> Vec<u32> {
+    if length <= tile {
+        return vec![0];
+    }
+
+    let step = tile.saturating_sub(overlap).max(1);
+    let mut offsets = (0..length - tile)
+        .step_by(step as usize)
+        .collect::<Vec<_>>();
+    offsets.push(length - tile);
+
+    offsets
+}
+
+/// linear ramp over `overlap` pixels on tile edges that are inside the image
+fn tile_feather(position: u32, offset: u32, tile: u32, length: u32, overlap: u32) -> f32 {
+    if overlap == 0 {
+        return 1.0;
+    }
+
+    let ramp = |distance: u32| ((distance + 1) as f32 / (overlap + 1) as f32).min(1.0);
+
+    let start = if offset > 0 { ramp(position) } else { 1.0 };
+    let end = if offset + tile < length { ramp(tile - 1 - position) } else { 1.0 };
+
+    start.min(end)
+}
+
+fn run_modnet(
+    session: &OrtSession,
+    input: &Array4<f32>,
+    handle: Option<&InferenceHandle>,
+) -> Result<Vec<Matte>, BevyOrtError> {
+    let input_values = inputs!["input" => input.view()]?;
+    let outputs = session.run_with(input_values, handle)?;
+    let output_value: &ort::Value = outputs.get("output")
+        .ok_or_else(|| BevyOrtError::MissingOutput("output".to_string()))?;
+
+    output_to_mattes(output_value)
 }
 
 
 pub fn modnet_output_to_luma_images(
     output_value: &ort::Value,
-) -> Vec<Image> {
-    let tensor = output_value.try_extract_tensor::<f32>().unwrap();
+) -> Result<Vec<Image>, BevyOrtError> {
+    modnet_output_to_images(output_value, MatteFormat::R8Unorm)
+}
+
+pub fn modnet_output_to_images(
+    output_value: &ort::Value,
+    format: MatteFormat,
+) -> Result<Vec<Image>, BevyOrtError> {
+    let images = output_to_mattes(output_value)?
+        .par_iter()
+        .map(|matte| matte_to_image(matte, format))
+        .collect::<Vec<_>>();
+
+    Ok(images)
+}
+
+/// full precision (height, width) mattes, e.g. for custom shaders or compositing
+pub fn modnet_output_to_arrays(
+    output_value: &ort::Value,
+) -> Result<Vec<Array2<f32>>, BevyOrtError> {
+    let arrays = output_to_mattes(output_value)?
+        .into_par_iter()
+        .map(matte_to_array)
+        .collect::<Vec<_>>();
+
+    Ok(arrays)
+}
+
+
+type Matte = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+fn output_to_mattes(
+    output_value: &ort::Value,
+) -> Result<Vec<Matte>, BevyOrtError> {
+    let tensor = output_value.try_extract_tensor::<f32>()?;
     let data = tensor.view();
 
     let shape = data.shape();
@@ -64,66 +777,678 @@ pub fn modnet_output_to_luma_images(
     let tensor_data = ArrayView4::from_shape((batch_size, 1, height, width), data.as_slice().unwrap())
         .expect("failed to create ArrayView4 from shape and data");
 
-    (0..batch_size)
+    let mattes = (0..batch_size)
         .into_par_iter()
         .map(|i| {
-            let mut imgbuf = ImageBuffer::<Luma<u8>, Vec<u8>>::new(width as u32, height as u32);
+            ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+                Luma([tensor_data[(i, 0, y as usize, x as usize)]])
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(mattes)
+}
 
-            for y in 0..height {
-                for x in 0..width {
-                    let pixel_value = tensor_data[(i, 0, y, x)];
-                    let pixel_value = (pixel_value.clamp(0.0, 1.0) * 255.0) as u8;
-                    imgbuf.put_pixel(x as u32, y as u32, Luma([pixel_value]));
+fn matte_to_array(matte: Matte) -> Array2<f32> {
+    let (width, height) = matte.dimensions();
+    Array2::from_shape_vec((height as usize, width as usize), matte.into_raw())
+        .expect("matte buffer does not match its dimensions")
+}
+
+fn matte_to_image(matte: &Matte, format: MatteFormat) -> Image {
+    let data = match format {
+        MatteFormat::R8Unorm => matte.pixels()
+            .map(|pixel| (pixel.0[0].clamp(0.0, 1.0) * 255.0) as u8)
+            .collect::<Vec<_>>(),
+        MatteFormat::R16Unorm => matte.pixels()
+            .flat_map(|pixel| ((pixel.0[0].clamp(0.0, 1.0) * 65535.0).round() as u16).to_le_bytes())
+            .collect::<Vec<_>>(),
+        MatteFormat::R16Float => matte.pixels()
+            .flat_map(|pixel| f16::from_f32(pixel.0[0]).to_le_bytes())
+            .collect::<Vec<_>>(),
+        MatteFormat::R32Float => bytemuck::cast_slice(matte.as_raw()).to_vec(),
+    };
+
+    Image::new(
+        Extent3d {
+            width: matte.width(),
+            height: matte.height(),
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        format.texture_format(),
+        RenderAssetUsages::all(),
+    )
+}
+
+/// reads a single channel matte image in any `MatteFormat`
+fn image_to_matte(image: &Image) -> Matte {
+    let (width, height) = (image.width(), image.height());
+
+    let data = match MatteFormat::from_texture_format(image.texture_descriptor.format) {
+        Some(MatteFormat::R8Unorm) => image.data.iter()
+            .map(|&value| value as f32 / 255.0)
+            .collect::<Vec<_>>(),
+        Some(MatteFormat::R16Unorm) => image.data.chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 65535.0)
+            .collect::<Vec<_>>(),
+        Some(MatteFormat::R16Float) => image.data.chunks_exact(2)
+            .map(|bytes| f16::from_le_bytes([bytes[0], bytes[1]]).to_f32())
+            .collect::<Vec<_>>(),
+        Some(MatteFormat::R32Float) => image.data.chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect::<Vec<_>>(),
+        None => return image.clone().try_into_dynamic().unwrap().to_luma32f(),
+    };
+
+    Matte::from_raw(width, height, data).expect("matte image data does not match its dimensions")
+}
+
+
+/// full precision (height, width) matte from a single channel `MatteFormat` image
+pub fn modnet_image_to_array(matte: &Image) -> Array2<f32> {
+    matte_to_array(image_to_matte(matte))
+}
+
+pub fn modnet_array_to_image(matte: &Array2<f32>, format: MatteFormat) -> Image {
+    let (height, width) = matte.dim();
+    let matte = Matte::from_raw(width as u32, height as u32, matte.iter().copied().collect())
+        .expect("matte array does not match its dimensions");
+
+    matte_to_image(&matte, format)
+}
+
+
+pub const TRIMAP_BACKGROUND: u8 = 0;
+pub const TRIMAP_UNKNOWN: u8 = 128;
+pub const TRIMAP_FOREGROUND: u8 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrimapConfig {
+    /// matte values at or above this are foreground
+    pub foreground_threshold: f32,
+    /// matte values at or below this are background
+    pub background_threshold: f32,
+    /// radius in pixels the foreground shrinks by
+    pub erosion: u32,
+    /// radius in pixels the non-background region grows by
+    pub dilation: u32,
+}
+
+impl Default for TrimapConfig {
+    fn default() -> Self {
+        Self {
+            foreground_threshold: 0.95,
+            background_threshold: 0.05,
+            erosion: 5,
+            dilation: 5,
+        }
+    }
+}
+
+/// (height, width) trimap of `TRIMAP_BACKGROUND`, `TRIMAP_UNKNOWN` and `TRIMAP_FOREGROUND` values
+pub fn modnet_trimap(
+    matte: &Array2<f32>,
+    config: &TrimapConfig,
+) -> Array2<u8> {
+    let foreground = matte.mapv(|v| v >= config.foreground_threshold);
+    let foreground = morphology(&foreground, config.erosion as usize, false);
+
+    let not_background = matte.mapv(|v| v > config.background_threshold);
+    let not_background = morphology(&not_background, config.dilation as usize, true);
+
+    Array2::from_shape_fn(matte.dim(), |index| {
+        if foreground[index] {
+            TRIMAP_FOREGROUND
+        } else if not_background[index] {
+            TRIMAP_UNKNOWN
+        } else {
+            TRIMAP_BACKGROUND
+        }
+    })
+}
+
+/// `R8Unorm` trimap image of a matte image
+pub fn modnet_trimap_image(
+    matte: &Image,
+    config: &TrimapConfig,
+) -> Image {
+    let trimap = modnet_trimap(&modnet_image_to_array(matte), config);
+    let (height, width) = trimap.dim();
+
+    Image::new(
+        Extent3d {
+            width: width as u32,
+            height: height as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        trimap.into_raw_vec(),
+        TextureFormat::R8Unorm,
+        RenderAssetUsages::all(),
+    )
+}
+
+/// square dilation (`grow`) or erosion of a mask, separable in x and y
+fn morphology(mask: &Array2<bool>, radius: usize, grow: bool) -> Array2<bool> {
+    if radius == 0 {
+        return mask.clone();
+    }
+
+    let (height, width) = mask.dim();
+    let pass = |mask: &Array2<bool>, horizontal: bool| {
+        Array2::from_shape_fn((height, width), |(y, x)| {
+            let (position, length) = if horizontal { (x, width) } else { (y, height) };
+            let range = position.saturating_sub(radius)..(position + radius + 1).min(length);
+
+            let mut values = range.map(|i| if horizontal { mask[[y, i]] } else { mask[[i, x]] });
+            if grow {
+                values.any(|v| v)
+            } else {
+                values.all(|v| v)
+            }
+        })
+    };
+
+    pass(&pass(mask, true), false)
+}
+
+/// edge-aware matte refinement guided by the luminance of `source`, resampled to the matte size if needed
+///
+/// larger `epsilon` values smooth more, see He et al. "Guided Image Filtering".
+pub fn modnet_guided_filter(
+    source: &Image,
+    matte: &Array2<f32>,
+    radius: u32,
+    epsilon: f32,
+) -> Array2<f32> {
+    let (height, width) = matte.dim();
+
+    let source = source.clone().try_into_dynamic().unwrap();
+    let source = if source.width() != width as u32 || source.height() != height as u32 {
+        source.resize_exact(width as u32, height as u32, FilterType::Triangle)
+    } else {
+        source
+    };
+    let guide = source.to_luma32f();
+    let guide = Array2::from_shape_vec((height, width), guide.into_raw()).unwrap();
+
+    let radius = radius as usize;
+    let mean_guide = box_filter(&guide, radius);
+    let mean_matte = box_filter(matte, radius);
+    let corr_guide = box_filter(&(&guide * &guide), radius);
+    let corr_guide_matte = box_filter(&(&guide * matte), radius);
+
+    let variance = &corr_guide - &(&mean_guide * &mean_guide);
+    let covariance = &corr_guide_matte - &(&mean_guide * &mean_matte);
+
+    let a = &covariance / &variance.mapv(|v| v + epsilon);
+    let b = &mean_matte - &(&a * &mean_guide);
+
+    let refined = &box_filter(&a, radius) * &guide + &box_filter(&b, radius);
+    refined.mapv(|v| v.clamp(0.0, 1.0))
+}
+
+/// mean over a (2 * radius + 1) square window, clamped to the array bounds
+fn box_filter(input: &Array2<f32>, radius: usize) -> Array2<f32> {
+    let (height, width) = input.dim();
+
+    let mut integral = Array2::<f64>::zeros((height + 1, width + 1));
+    for y in 0..height {
+        for x in 0..width {
+            integral[[y + 1, x + 1]] = input[[y, x]] as f64
+                + integral[[y, x + 1]]
+                + integral[[y + 1, x]]
+                - integral[[y, x]];
+        }
+    }
+
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        let (y0, y1) = (y.saturating_sub(radius), (y + radius + 1).min(height));
+        let (x0, x1) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+
+        let sum = integral[[y1, x1]] - integral[[y0, x1]] - integral[[y1, x0]] + integral[[y0, x0]];
+        (sum / ((y1 - y0) * (x1 - x0)) as f64) as f32
+    })
+}
+
+/// sets enclosed regions below `threshold` to foreground, optionally only those up to `max_area` pixels
+pub fn modnet_fill_holes(
+    matte: &Array2<f32>,
+    threshold: f32,
+    max_area: Option<usize>,
+) -> Array2<f32> {
+    let (height, width) = matte.dim();
+    let mut filled = matte.clone();
+    let mut visited = Array2::from_elem((height, width), false);
+
+    for start_y in 0..height {
+        for start_x in 0..width {
+            if visited[[start_y, start_x]] || matte[[start_y, start_x]] >= threshold {
+                continue;
+            }
+
+            let mut region = Vec::new();
+            let mut touches_border = false;
+            let mut stack = vec![(start_y, start_x)];
+            visited[[start_y, start_x]] = true;
+
+            while let Some((y, x)) = stack.pop() {
+                region.push((y, x));
+                touches_border |= y == 0 || x == 0 || y == height - 1 || x == width - 1;
+
+                let neighbours = [
+                    (y.wrapping_sub(1), x),
+                    (y + 1, x),
+                    (y, x.wrapping_sub(1)),
+                    (y, x + 1),
+                ];
+                for (ny, nx) in neighbours {
+                    if ny < height && nx < width && !visited[[ny, nx]] && matte[[ny, nx]] < threshold {
+                        visited[[ny, nx]] = true;
+                        stack.push((ny, nx));
+                    }
+                }
+            }
+
+            let small_enough = match max_area {
+                Some(max_area) => region.len() <= max_area,
+                None => true,
+            };
+            if !touches_border && small_enough {
+                for index in region {
+                    filled[index] = 1.0;
                 }
             }
+        }
+    }
+
+    filled
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemporalFilter {
+    /// exponential moving average, `strength` is the weight of the previous output
+    Ema,
+    /// exponential moving average that follows the current frame where the matte changes by more than `motion_threshold`
+    MotionAware {
+        motion_threshold: f32,
+    },
+    /// MODNet one-frame delay, replaces values that flicker for a single frame
+    ///
+    /// outputs are delayed by one frame.
+    Ofd {
+        threshold: f32,
+    },
+}
+
+impl Default for TemporalFilter {
+    fn default() -> Self {
+        TemporalFilter::MotionAware {
+            motion_threshold: 0.2,
+        }
+    }
+}
+
+/// temporal stabilization state for one stream of modnet mattes, e.g. one per video entity
+#[derive(Component, Debug, Clone)]
+pub struct ModnetTemporalFilter {
+    pub filter: TemporalFilter,
+    /// 0.0 disables smoothing, values closer to 1.0 smooth more
+    pub strength: f32,
+    /// mean absolute matte difference between consecutive frames that resets the history
+    pub scene_cut_threshold: f32,
+    history: Vec<Matte>,
+}
+
+impl Default for ModnetTemporalFilter {
+    fn default() -> Self {
+        Self::new(TemporalFilter::default())
+    }
+}
+
+impl ModnetTemporalFilter {
+    pub fn new(filter: TemporalFilter) -> Self {
+        Self {
+            filter,
+            strength: 0.6,
+            scene_cut_threshold: 0.25,
+            history: Vec::new(),
+        }
+    }
+
+    pub fn with_strength(mut self, strength: f32) -> Self {
+        self.strength = strength.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_scene_cut_threshold(mut self, threshold: f32) -> Self {
+        self.scene_cut_threshold = threshold;
+        self
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+    }
+
+    /// filters the next matte of the stream, keeping its `MatteFormat`
+    pub fn apply(&mut self, matte: &Image) -> Image {
+        let format = MatteFormat::from_texture_format(matte.texture_descriptor.format).unwrap_or_default();
+        matte_to_image(&self.apply_matte(image_to_matte(matte)), format)
+    }
+
+    fn apply_matte(&mut self, matte: Matte) -> Matte {
+        if self.is_scene_cut(&matte) {
+            self.reset();
+        }
+
+        match self.filter {
+            TemporalFilter::Ema | TemporalFilter::MotionAware { .. } => {
+                let output = match self.history.last() {
+                    Some(previous) => {
+                        let motion_threshold = match self.filter {
+                            TemporalFilter::MotionAware { motion_threshold } => Some(motion_threshold),
+                            _ => None,
+                        };
+
+                        let data = matte.as_raw()
+                            .iter()
+                            .zip(previous.as_raw().iter())
+                            .map(|(&current, &previous)| {
+                                let weight = match motion_threshold {
+                                    Some(threshold) if threshold > 0.0 => {
+                                        let motion = ((current - previous).abs() / threshold).min(1.0);
+                                        self.strength * (1.0 - motion)
+                                    },
+                                    Some(_) => 0.0,
+                                    None => self.strength,
+                                };
+
+                                previous * weight + current * (1.0 - weight)
+                            })
+                            .collect::<Vec<_>>();
+
+                        Matte::from_raw(matte.width(), matte.height(), data).unwrap()
+                    },
+                    None => matte.clone(),
+                };
+
+                self.history = vec![output.clone()];
+                output
+            },
+            TemporalFilter::Ofd { threshold } => {
+                self.history.push(matte);
+                if self.history.len() > 3 {
+                    self.history.remove(0);
+                }
+
+                match self.history.as_slice() {
+                    [before, current, after] => {
+                        let data = before.as_raw()
+                            .iter()
+                            .zip(current.as_raw().iter())
+                            .zip(after.as_raw().iter())
+                            .map(|((&before, &current), &after)| {
+                                let flicker = (before - after).abs() <= threshold
+                                    && (current - before).abs() > threshold
+                                    && (current - after).abs() > threshold;
+
+                                if flicker {
+                                    (before + after) / 2.0
+                                } else {
+                                    current
+                                }
+                            })
+                            .collect::<Vec<_>>();
+
+                        let output = Matte::from_raw(current.width(), current.height(), data).unwrap();
+                        self.history[1] = output.clone();
+                        output
+                    },
+                    [first, ..] => first.clone(),
+                    [] => unreachable!(),
+                }
+            },
+        }
+    }
+
+    fn is_scene_cut(&self, matte: &Matte) -> bool {
+        let Some(previous) = self.history.last() else {
+            return false;
+        };
+
+        if previous.dimensions() != matte.dimensions() {
+            return true;
+        }
+
+        let difference = previous.as_raw()
+            .iter()
+            .zip(matte.as_raw().iter())
+            .map(|(a, b)| (a - b).abs())
+            .sum::<f32>() / matte.as_raw().len().max(1) as f32;
+
+        difference > self.scene_cut_threshold
+    }
+}
 
-            Image::new(
-                Extent3d {
-                    width: width as u32,
-                    height: height as u32,
-                    depth_or_array_layers: 1,
+
+#[derive(Debug, Clone, Copy)]
+pub enum Background<'a> {
+    Image(&'a Image),
+    Color(Color),
+}
+
+/// cuts the subject out of `source` as premultiplied `Rgba8UnormSrgb`
+///
+/// `matte` is a single channel `MatteFormat` image, resampled to the source size if needed.
+pub fn modnet_cutout(
+    source: &Image,
+    matte: &Image,
+) -> Image {
+    composite(source, matte, None)
+}
+
+/// composites the subject of `source` over `background`, resampled to the source size if needed
+pub fn modnet_composite(
+    source: &Image,
+    matte: &Image,
+    background: Background,
+) -> Image {
+    let background = match background {
+        Background::Image(image) => {
+            let image = image.clone().try_into_dynamic().unwrap();
+            let image = if image.width() != source.width() || image.height() != source.height() {
+                image.resize_exact(source.width(), source.height(), FilterType::Triangle)
+            } else {
+                image
+            };
+
+            BackgroundPixels::Pixels(image.to_rgba8())
+        },
+        Background::Color(color) => BackgroundPixels::Color(color.as_linear_rgba_f32()),
+    };
+
+    composite(source, matte, Some(background))
+}
+
+enum BackgroundPixels {
+    Pixels(RgbaImage),
+    Color([f32; 4]),
+}
+
+fn composite(
+    source: &Image,
+    matte: &Image,
+    background: Option<BackgroundPixels>,
+) -> Image {
+    let (width, height) = (source.width(), source.height());
+
+    let source = source.clone().try_into_dynamic().unwrap().to_rgba8();
+    let matte = image_to_matte(matte);
+    let matte = if matte.dimensions() != (width, height) {
+        image::imageops::resize(&matte, width, height, FilterType::Triangle)
+    } else {
+        matte
+    };
+
+    let data = source.as_raw()
+        .par_chunks_exact(4)
+        .zip(matte.as_raw().par_iter())
+        .enumerate()
+        .flat_map_iter(|(i, (pixel, &alpha))| {
+            let alpha = alpha.clamp(0.0, 1.0) * pixel[3] as f32 / 255.0;
+            let foreground = [0, 1, 2].map(|c| srgb_to_linear(pixel[c]) * alpha);
+
+            let under = match &background {
+                None => [0.0; 4],
+                Some(BackgroundPixels::Color(color)) => {
+                    [color[0] * color[3], color[1] * color[3], color[2] * color[3], color[3]]
+                },
+                Some(BackgroundPixels::Pixels(pixels)) => {
+                    let pixel = pixels.get_pixel(i as u32 % width, i as u32 / width);
+                    let background_alpha = pixel.0[3] as f32 / 255.0;
+                    let [r, g, b] = [0, 1, 2].map(|c| srgb_to_linear(pixel.0[c]) * background_alpha);
+                    [r, g, b, background_alpha]
                 },
-                TextureDimension::D2,
-                imgbuf.into_raw(),
-                TextureFormat::R8Unorm,
-                RenderAssetUsages::all(),
-            )
+            };
+
+            let out_alpha = alpha + under[3] * (1.0 - alpha);
+            let premultiplied = [0, 1, 2].map(|c| foreground[c] + under[c] * (1.0 - alpha));
+
+            // cutouts stay premultiplied, composites are stored with straight alpha
+            let color = match &background {
+                None => premultiplied,
+                Some(_) if out_alpha > 0.0 => premultiplied.map(|c| c / out_alpha),
+                Some(_) => [0.0; 3],
+            };
+
+            [
+                linear_to_srgb(color[0]),
+                linear_to_srgb(color[1]),
+                linear_to_srgb(color[2]),
+                (out_alpha.clamp(0.0, 1.0) * 255.0).round() as u8,
+            ]
         })
-        .collect::<Vec<_>>()
+        .collect::<Vec<u8>>();
+
+    Image::new(
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::all(),
+    )
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+
+    (value * 255.0).round() as u8
 }
 
 
+/// images with different target shapes are padded to a common shape
 pub fn images_to_modnet_input(
     images: &[&Image],
     max_size: Option<(u32, u32)>,
+    config: &ModnetConfig,
 ) -> Array4<f32> {
+    let prepared = prepare_images(images, max_size, config);
+
+    stack_images(&prepared.iter().collect::<Vec<_>>(), padded_shape(&prepared))
+}
+
+
+struct PreparedImage {
+    /// (3, height, width)
+    tensor: Array3<f32>,
+    scale: Vec2,
+}
+
+impl PreparedImage {
+    fn shape(&self) -> (usize, usize) {
+        (self.tensor.shape()[1], self.tensor.shape()[2])
+    }
+}
+
+fn prepare_images(
+    images: &[&Image],
+    max_size: Option<(u32, u32)>,
+    config: &ModnetConfig,
+) -> Vec<PreparedImage> {
     if images.is_empty() {
         panic!("no images provided");
     }
 
-    let ref_size = 512;
-    let &first_image = images.first().unwrap();
-
-    let (x_scale, y_scale) = get_scale_factor(first_image.height(), first_image.width(), ref_size, max_size);
-
-    let processed_images: Vec<Array4<f32>> = images
+    images
         .par_iter()
         .map(|&image| {
-            let resized_image = resize_image(&image.clone().try_into_dynamic().unwrap(), x_scale, y_scale);
-            image_to_ndarray(&resized_image)
+            let (width, height) = get_target_size(image.height(), image.width(), config.ref_size, config.alignment, max_size);
+            let resized_image = image.clone()
+                .try_into_dynamic()
+                .unwrap()
+                .resize_exact(width, height, config.resize_filter)
+                .to_rgb8();
+
+            PreparedImage {
+                tensor: image_to_ndarray(&resized_image, config.normalization),
+                scale: Vec2::new(
+                    width as f32 / image.width() as f32,
+                    height as f32 / image.height() as f32,
+                ),
+            }
         })
-        .collect();
+        .collect()
+}
+
+fn padded_shape(images: &[PreparedImage]) -> (usize, usize) {
+    images.iter()
+        .map(PreparedImage::shape)
+        .fold((0, 0), |(max_h, max_w), (h, w)| (max_h.max(h), max_w.max(w)))
+}
 
-    let aggregate = Array::from_shape_vec(
-        (processed_images.len(), processed_images[0].shape()[1], processed_images[0].shape()[2], processed_images[0].shape()[3]),
-        processed_images.iter().flat_map(|a| a.iter().cloned()).collect(),
-    ).unwrap();
+/// stacks images into a (batch, 3, height, width) tensor, zero padding the bottom and right
+fn stack_images(
+    images: &[&PreparedImage],
+    (height, width): (usize, usize),
+) -> Array4<f32> {
+    let mut input = Array4::zeros((images.len(), 3, height, width));
+
+    for (i, image) in images.iter().enumerate() {
+        let (image_height, image_width) = image.shape();
+        input
+            .slice_mut(s![i, .., ..image_height, ..image_width])
+            .assign(&image.tensor);
+    }
 
-    aggregate
+    input
 }
 
 
-fn get_scale_factor(im_h: u32, im_w: u32, ref_size: u32, max_size: Option<(u32, u32)>) -> (f32, f32) {
+/// returns the (width, height) of the `alignment`-aligned inference resolution
+fn get_target_size(im_h: u32, im_w: u32, ref_size: u32, alignment: u32, max_size: Option<(u32, u32)>) -> (u32, u32) {
+    let alignment = alignment.max(1);
+
     let scale_factor_max = max_size.map_or(1.0, |(max_w, max_h)| {
         f32::min(max_w as f32 / im_w as f32, max_h as f32 / im_h as f32)
     });
@@ -140,17 +1465,18 @@ fn get_scale_factor(im_h: u32, im_w: u32, ref_size: u32, max_size: Option<(u32,
     let final_scale_w = f32::min(scale_factor_max, scale_factor_ref_w);
     let final_scale_h = f32::min(scale_factor_max, scale_factor_ref_h);
 
-    let final_w = ((im_w as f32 * final_scale_w).round() as u32) - ((im_w as f32 * final_scale_w).round() as u32) % 32;
-    let final_h = ((im_h as f32 * final_scale_h).round() as u32) - ((im_h as f32 * final_scale_h).round() as u32) % 32;
+    let final_w = ((im_w as f32 * final_scale_w).round() as u32) - ((im_w as f32 * final_scale_w).round() as u32) % alignment;
+    let final_h = ((im_h as f32 * final_scale_h).round() as u32) - ((im_h as f32 * final_scale_h).round() as u32) % alignment;
 
-    (final_w as f32 / im_w as f32, final_h as f32 / im_h as f32)
+    (final_w.max(alignment), final_h.max(alignment))
 }
 
 
-fn image_to_ndarray(img: &RgbImage) -> Array4<f32> {
+fn image_to_ndarray(img: &RgbImage, normalization: ModnetNormalization) -> Array3<f32> {
     let (width, height) = img.dimensions();
+    let (mean, std) = normalization.mean_std();
 
-    let arr = Array::from_shape_fn((1, 3, height as usize, width as usize), |(_, c, y, x)| {
+    let arr = Array::from_shape_fn((3, height as usize, width as usize), |(c, y, x)| {
         let pixel = img.get_pixel(x as u32, y as u32);
         let channel_value = match c {
             0 => pixel[0],
@@ -158,16 +1484,186 @@ fn image_to_ndarray(img: &RgbImage) -> Array4<f32> {
             2 => pixel[2],
             _ => unreachable!(),
         };
-        (channel_value as f32 - 127.5) / 127.5
+        (channel_value as f32 / 255.0 - mean[c]) / std[c]
     });
 
     arr
 }
 
-fn resize_image(image: &DynamicImage, x_scale: f32, y_scale: f32) -> RgbImage {
-    let (width, height) = image.dimensions();
-    let new_width = (width as f32 * x_scale) as u32;
-    let new_height = (height as f32 * y_scale) as u32;
 
-    image.resize_exact(new_width, new_height, FilterType::Triangle).to_rgb8()
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::image;
+
+    use super::*;
+
+    fn matte(width: u32, height: u32, value: u8) -> Image {
+        Image::new(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            vec![value; (width * height) as usize],
+            TextureFormat::R8Unorm,
+            RenderAssetUsages::all(),
+        )
+    }
+
+    #[test]
+    fn test_plugin_systems_initialize() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()));
+        app.init_asset::<Image>();
+        app.init_asset::<Onnx>();
+        app.add_plugins(ModnetPlugin);
+
+        // conflicting system parameters panic on the first update
+        app.update();
+    }
+
+    #[test]
+    fn test_cutout_and_composite_use_matte_alpha() {
+        let source = image(8, 4);
+
+        let cutout = modnet_cutout(&source, &matte(4, 2, 0));
+        assert_eq!((cutout.width(), cutout.height()), (8, 4), "cutout should match the source size.");
+        assert!(cutout.data.chunks(4).all(|pixel| pixel == [0, 0, 0, 0]), "empty matte should produce a transparent cutout.");
+
+        let composite = modnet_composite(&source, &matte(8, 4, 0), Background::Color(Color::BLACK));
+        assert!(composite.data.chunks(4).all(|pixel| pixel == [0, 0, 0, 255]), "empty matte should show the background.");
+
+        let composite = modnet_composite(&source, &matte(8, 4, 255), Background::Color(Color::BLACK));
+        assert!(composite.data.chunks(4).all(|pixel| pixel == [255, 255, 255, 255]), "full matte should show the source.");
+    }
+
+    fn raw_matte(value: f32) -> Matte {
+        Matte::from_pixel(4, 4, Luma([value]))
+    }
+
+    #[test]
+    fn test_temporal_filter_smooths_and_resets() {
+        let mut filter = ModnetTemporalFilter::new(TemporalFilter::Ema)
+            .with_strength(0.5)
+            .with_scene_cut_threshold(0.5);
+
+        filter.apply_matte(raw_matte(0.0));
+        let smoothed = filter.apply_matte(raw_matte(0.4));
+        assert!((smoothed.get_pixel(0, 0).0[0] - 0.2).abs() < 1e-6, "ema should blend with the previous output.");
+
+        let cut = filter.apply_matte(raw_matte(1.0));
+        assert_eq!(cut.get_pixel(0, 0).0[0], 1.0, "scene cut should reset the history.");
+    }
+
+    #[test]
+    fn test_ofd_removes_single_frame_flicker() {
+        let mut filter = ModnetTemporalFilter::new(TemporalFilter::Ofd { threshold: 0.1 })
+            .with_scene_cut_threshold(1.0);
+
+        filter.apply_matte(raw_matte(1.0));
+        filter.apply_matte(raw_matte(0.0));
+        let output = filter.apply_matte(raw_matte(1.0));
+        assert_eq!(output.get_pixel(0, 0).0[0], 1.0, "flickering frame should be replaced by its neighbours.");
+    }
+
+    #[test]
+    fn test_matte_formats_round_trip() {
+        let matte = Matte::from_fn(3, 2, |x, y| Luma([(x + y * 3) as f32 / 5.0]));
+
+        for format in [MatteFormat::R8Unorm, MatteFormat::R16Unorm, MatteFormat::R16Float, MatteFormat::R32Float] {
+            let image = matte_to_image(&matte, format);
+            assert_eq!(image.texture_descriptor.format, format.texture_format(), "image should use the requested format.");
+
+            let tolerance = if format == MatteFormat::R8Unorm { 1.0 / 255.0 } else { 1e-3 };
+            let round_trip = image_to_matte(&image);
+            assert!(
+                matte.pixels().zip(round_trip.pixels()).all(|(a, b)| (a.0[0] - b.0[0]).abs() <= tolerance),
+                "matte should round trip through {:?}.",
+                format,
+            );
+        }
+
+        let array = matte_to_array(matte);
+        assert_eq!(array.dim(), (2, 3), "raw matte should be (height, width).");
+        assert_eq!(array[[1, 2]], 1.0, "raw matte should keep full precision.");
+    }
+
+    #[test]
+    fn test_tiles_cover_the_image() {
+        assert_eq!(tile_offsets(1000, 512, 64), vec![0, 448, 488], "tiles should overlap and end at the image edge.");
+        assert_eq!(tile_offsets(300, 512, 64), vec![0], "small images should use a single tile.");
+
+        assert_eq!(tile_feather(0, 0, 512, 1000, 64), 1.0, "image borders should not be feathered.");
+        assert!(tile_feather(0, 448, 512, 1000, 64) < 0.1, "interior tile edges should be feathered.");
+        assert_eq!(tile_feather(256, 448, 512, 1000, 64), 1.0, "tile centers should have full weight.");
+    }
+
+    #[test]
+    fn test_trimap_and_hole_filling() {
+        let mut matte = Array2::<f32>::zeros((9, 9));
+        matte.slice_mut(s![2..7, 2..7]).fill(1.0);
+        matte[[4, 4]] = 0.0;
+
+        let filled = modnet_fill_holes(&matte, 0.5, None);
+        assert_eq!(filled[[4, 4]], 1.0, "enclosed hole should be filled.");
+        assert_eq!(filled[[0, 0]], 0.0, "background touching the border should be kept.");
+        assert_eq!(modnet_fill_holes(&matte, 0.5, Some(0))[[4, 4]], 0.0, "holes above max_area should be kept.");
+
+        let config = TrimapConfig {
+            erosion: 1,
+            dilation: 1,
+            ..default()
+        };
+        let trimap = modnet_trimap(&filled, &config);
+        assert_eq!(trimap[[4, 4]], TRIMAP_FOREGROUND, "eroded interior should be foreground.");
+        assert_eq!(trimap[[2, 2]], TRIMAP_UNKNOWN, "matte edge should be unknown.");
+        assert_eq!(trimap[[1, 4]], TRIMAP_UNKNOWN, "dilated edge should be unknown.");
+        assert_eq!(trimap[[0, 0]], TRIMAP_BACKGROUND, "far background should stay background.");
+    }
+
+    #[test]
+    fn test_guided_filter_keeps_constant_matte() {
+        let source = image(6, 4);
+        let matte = Array2::from_elem((4, 6), 0.75);
+
+        let refined = modnet_guided_filter(&source, &matte, 2, 1e-3);
+        assert!(refined.iter().all(|&v| (v - 0.75).abs() < 1e-4), "constant matte should be unchanged.");
+    }
+
+    #[test]
+    fn test_target_size_is_aligned() {
+        let (width, height) = get_target_size(1080, 1920, 512, 32, None);
+        assert_eq!((width % 32, height % 32), (0, 0));
+
+        let (width, height) = get_target_size(1080, 1920, 512, 32, Some((512, 512)));
+        assert_eq!((width, height), (512, 288));
+    }
+
+    #[test]
+    fn test_config_alignment_and_normalization() {
+        let (width, height) = get_target_size(1080, 1920, 320, 64, Some((1000, 1000)));
+        assert_eq!((width % 64, height % 64), (0, 0), "target size should follow the configured alignment.");
+
+        let white = RgbImage::from_pixel(2, 2, image::Rgb([255, 255, 255]));
+        let symmetric = image_to_ndarray(&white, ModnetNormalization::Symmetric);
+        assert!(symmetric.iter().all(|&v| (v - 1.0).abs() < 1e-6), "symmetric normalization should map white to 1.0.");
+
+        let imagenet = image_to_ndarray(&white, ModnetNormalization::ImageNet);
+        assert!((imagenet[[0, 0, 0]] - (1.0 - 0.485) / 0.229).abs() < 1e-4, "imagenet normalization should use the red mean and std.");
+    }
+
+    #[test]
+    fn test_mixed_size_batch_is_padded() {
+        let landscape = image(640, 480);
+        let portrait = image(480, 640);
+
+        let input = images_to_modnet_input(&[&landscape, &portrait], None, &ModnetConfig::default());
+        assert_eq!(input.shape(), &[2, 3, 640, 640]);
+
+        assert_eq!(input[[0, 0, 0, 0]], 1.0);
+        assert_eq!(input[[0, 0, 600, 0]], 0.0, "the landscape image should be padded below.");
+        assert_eq!(input[[1, 0, 0, 600]], 0.0, "the portrait image should be padded to the right.");
+    }
 }
@@ -1,10 +1,31 @@
-use bevy::prelude::*;
+use std::{
+    collections::HashMap,
+};
+
+use bevy::{
+    prelude::*,
+    asset::{
+        AssetLoader,
+        AsyncReadExt,
+        LoadContext,
+        io::Reader,
+    },
+    utils::BoxedFuture,
+};
 use image::GenericImageView;
-use ndarray::{Array, ArrayD, Axis};
+use ndarray::{Array, ArrayD, ArrayViewD, Axis};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::{
+    cache::{
+        CacheKey,
+        CacheKeyBuilder,
+        InferenceCache,
+    },
+    inference::InferenceHandle,
     inputs,
+    BevyOrtError,
     Onnx,
     OrtSession,
 };
@@ -18,6 +39,9 @@ pub struct BoundingBox {
     pub y2: f32,
     pub class_id: usize,
     pub prob: f32,
+    /// class name from the model's `YoloLabels`, if known
+    #[serde(default)]
+    pub label: Option<String>,
 }
 
 
@@ -25,37 +49,602 @@ pub struct YoloPlugin;
 impl Plugin for YoloPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<Yolo>();
+        app.init_asset::<YoloLabels>();
+        app.register_asset_loader(YoloLabelsLoader);
+        app.add_systems(PreUpdate, sync_yolo_labels);
     }
 }
 
 #[derive(Resource, Default)]
 pub struct Yolo {
     pub onnx: Handle<Onnx>,
+    pub config: YoloConfig,
+    /// optional labels asset, copied into `config.labels` once loaded
+    pub labels: Handle<YoloLabels>,
+}
+
+/// copies a loaded labels asset into `config.labels`, otherwise resolves them once from the model session
+fn sync_yolo_labels(
+    mut yolo: ResMut<Yolo>,
+    mut events: EventReader<AssetEvent<YoloLabels>>,
+    labels: Res<Assets<YoloLabels>>,
+    onnx_assets: Res<Assets<Onnx>>,
+    mut model_labels: Local<Option<(AssetId<Onnx>, Option<YoloLabels>)>>,
+) {
+    let labels_id = yolo.labels.id();
+    let modified = events.read().any(|event| match event {
+        AssetEvent::Added { id } | AssetEvent::Modified { id } => *id == labels_id,
+        _ => false,
+    });
+
+    if let Some(asset) = labels.get(labels_id) {
+        // `yolo.labels` may have been changed to an already loaded asset
+        if (modified || yolo.is_changed()) && yolo.config.labels.as_ref() != Some(asset) {
+            yolo.config.labels = Some(asset.clone());
+        }
+        return;
+    }
+
+    let onnx_id = yolo.onnx.id();
+    let previous = match model_labels.as_ref() {
+        Some((id, _)) if *id == onnx_id => return,
+        Some((_, resolved)) => resolved.clone(),
+        None => None,
+    };
+
+    // the model changed, drop labels resolved from the previous one
+    if previous.is_some() && yolo.config.labels == previous {
+        yolo.config.labels = None;
+    }
+    *model_labels = None;
+
+    if yolo.config.labels.is_some() {
+        return;
+    }
+
+    let Some(onnx) = onnx_assets.get(onnx_id) else {
+        return;
+    };
+    let Ok(session_lock) = onnx.session.lock() else {
+        return;
+    };
+    let Some(session) = session_lock.as_ref() else {
+        return;
+    };
+
+    let resolved = YoloLabels::from_model(session);
+    if resolved.is_some() {
+        yolo.config.labels = resolved.clone();
+    }
+    *model_labels = Some((onnx_id, resolved));
+}
+
+
+/// class names indexed by class id
+#[derive(Asset, TypePath, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct YoloLabels {
+    pub names: Vec<String>,
+}
+
+impl YoloLabels {
+    pub fn coco() -> Self {
+        Self {
+            names: YOLO_CLASSES.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    /// the model's `names` metadata, falling back to COCO names for 80 class models
+    pub fn from_model(session: &OrtSession) -> Option<Self> {
+        if let Ok(Some(labels)) = Self::from_session(session) {
+            return Some(labels);
+        }
+
+        let classes = session.outputs()[0].output_type
+            .tensor_dimensions()
+            .and_then(|dimensions| dimensions.get(1).copied());
+
+        (classes == Some(4 + YOLO_CLASSES.len() as i64)).then(Self::coco)
+    }
+
+    /// reads the `names` custom metadata written by ultralytics exports
+    pub fn from_session(session: &OrtSession) -> Result<Option<Self>, String> {
+        let metadata = session.metadata().map_err(|e| e.to_string())?;
+
+        match metadata.custom("names").map_err(|e| e.to_string())? {
+            Some(names) => Self::parse(&names).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// parses one name per line, an ultralytics data YAML `names` entry, or a dict/list literal such as `{0: 'person', 1: 'bicycle'}`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+
+        if text.starts_with('{') || text.starts_with('[') {
+            return parse_inline_names(text);
+        }
+
+        let lines = text.lines().collect::<Vec<_>>();
+        let Some(start) = lines.iter().position(|line| line.trim_start().starts_with("names:")) else {
+            return Ok(Self {
+                names: lines.iter()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty())
+                    .map(String::from)
+                    .collect(),
+            });
+        };
+
+        let inline = lines[start].trim_start()["names:".len()..].trim();
+        if !inline.is_empty() {
+            return parse_inline_names(inline);
+        }
+
+        let mut entries = Vec::new();
+        for line in &lines[start + 1..] {
+            let entry = line.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            // the next top level key ends the names block
+            if !line.starts_with(char::is_whitespace) && !entry.starts_with('-') {
+                break;
+            }
+
+            match entry.strip_prefix('-') {
+                Some(name) => entries.push((entries.len(), unquote(name))),
+                None => {
+                    let (index, name) = entry.split_once(':')
+                        .ok_or_else(|| format!("invalid names entry: {}", entry))?;
+                    let index = index.trim().parse::<usize>().map_err(|e| e.to_string())?;
+
+                    entries.push((index, unquote(name)));
+                },
+            }
+        }
+
+        Ok(names_from_entries(entries))
+    }
+
+    pub fn get(&self, class_id: usize) -> Option<&str> {
+        self.names.get(class_id).map(String::as_str)
+    }
+}
+
+fn parse_inline_names(text: &str) -> Result<YoloLabels, String> {
+    let is_dict = text.starts_with('{');
+    let inner = text.get(1..text.len().saturating_sub(1)).unwrap_or_default();
+
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut quote = None;
+    for c in inner.chars() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), _) if open == c => quote = None,
+            (None, ',') => items.push(std::mem::take(&mut item)),
+            _ => {},
+        }
+
+        if quote.is_some() || c != ',' {
+            item.push(c);
+        }
+    }
+    items.push(item);
+
+    let mut entries = Vec::new();
+    for item in items.iter().filter(|item| !item.trim().is_empty()) {
+        if is_dict {
+            let (index, name) = item.split_once(':')
+                .ok_or_else(|| format!("invalid names entry: {}", item))?;
+            let index = index.trim().parse::<usize>().map_err(|e| e.to_string())?;
+
+            entries.push((index, unquote(name)));
+        } else {
+            entries.push((entries.len(), unquote(item)));
+        }
+    }
+
+    Ok(names_from_entries(entries))
+}
+
+fn unquote(text: &str) -> String {
+    let text = text.trim();
+
+    for quote in ['\'', '"'] {
+        if let Some(inner) = text.strip_prefix(quote).and_then(|text| text.strip_suffix(quote)) {
+            return inner.to_string();
+        }
+    }
+
+    text.to_string()
+}
+
+/// missing class ids are named by their index
+fn names_from_entries(entries: Vec<(usize, String)>) -> YoloLabels {
+    let len = entries.iter().map(|(index, _)| index + 1).max().unwrap_or(0);
+
+    let mut names = (0..len).map(|index| index.to_string()).collect::<Vec<_>>();
+    for (index, name) in entries {
+        names[index] = name;
+    }
+
+    YoloLabels { names }
+}
+
+
+#[derive(Debug, Error)]
+pub enum YoloLabelsError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid labels: {0}")]
+    Parse(String),
+}
+
+#[derive(Default)]
+pub struct YoloLabelsLoader;
+
+impl AssetLoader for YoloLabelsLoader {
+    type Asset = YoloLabels;
+    type Settings = ();
+    type Error = YoloLabelsError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a Self::Settings,
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+        Box::pin(async move {
+            let mut text = String::new();
+            reader.read_to_string(&mut text).await?;
+
+            YoloLabels::parse(&text).map_err(YoloLabelsError::Parse)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["labels.txt", "labels.yaml", "labels.yml"]
+    }
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ClassFilter {
+    #[default]
+    All,
+    Allow(Vec<usize>),
+    Deny(Vec<usize>),
+}
+
+impl ClassFilter {
+    pub fn allows(&self, class_id: usize) -> bool {
+        match self {
+            ClassFilter::All => true,
+            ClassFilter::Allow(classes) => classes.contains(&class_id),
+            ClassFilter::Deny(classes) => !classes.contains(&class_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct YoloConfig {
+    pub preprocess: YoloPreprocess,
+    pub iou_threshold: f32,
+    /// minimum class probability of a detection
+    pub confidence_threshold: f32,
+    /// overrides `confidence_threshold` for specific class ids
+    pub class_thresholds: HashMap<usize, f32>,
+    pub classes: ClassFilter,
+    /// highest probability detections kept per image after nms
+    pub max_detections: Option<usize>,
+    /// minimum box area in source image pixels
+    pub min_box_area: f32,
+    /// class names, filled in by `YoloPlugin` (or `with_model_labels`) from the model when unset
+    pub labels: Option<YoloLabels>,
+}
+
+impl Default for YoloConfig {
+    fn default() -> Self {
+        Self {
+            preprocess: YoloPreprocess::default(),
+            iou_threshold: 0.5,
+            confidence_threshold: 0.5,
+            class_thresholds: HashMap::new(),
+            classes: ClassFilter::All,
+            max_detections: Some(300),
+            min_box_area: 0.0,
+            labels: None,
+        }
+    }
+}
+
+impl YoloConfig {
+    /// sets unset `labels` with `YoloLabels::from_model`, resolve once per session rather than per inference
+    pub fn with_model_labels(mut self, session: &OrtSession) -> Self {
+        if self.labels.is_none() {
+            self.labels = YoloLabels::from_model(session);
+        }
+        self
+    }
+
+    pub fn threshold(&self, class_id: usize) -> f32 {
+        self.class_thresholds
+            .get(&class_id)
+            .copied()
+            .unwrap_or(self.confidence_threshold)
+    }
 }
 
 
-// TODO: support yolo input batching
 pub fn yolo_inference(
     session: &OrtSession,
     image: &Image,
-    iou_threshold: f32,
+    config: &YoloConfig,
+) -> Vec<BoundingBox> {
+    yolo_inference_with_handle(session, image, config, None).unwrap()
+}
+
+/// `yolo_inference` with each session run controlled by `handle`
+pub fn yolo_inference_with_handle(
+    session: &OrtSession,
+    image: &Image,
+    config: &YoloConfig,
+    handle: Option<&InferenceHandle>,
+) -> Result<Vec<BoundingBox>, BevyOrtError> {
+    let detections = yolo_inference_batch_with_handle(session, &[image], config, handle)?
+        .pop()
+        .unwrap_or_default();
+
+    Ok(detections)
+}
+
+/// `yolo_inference` through `cache`, `model` is e.g. `CacheKey::model`
+pub fn yolo_inference_cached(
+    cache: &InferenceCache,
+    model: CacheKeyBuilder,
+    session: &OrtSession,
+    image: &Image,
+    config: &YoloConfig,
 ) -> Vec<BoundingBox> {
-    let width = image.width();
-    let height = image.height();
+    cache.get_or_run_persistent(
+        yolo_cache_key(model, image, config),
+        || yolo_inference(session, image, config),
+    )
+}
 
-    let model_width = session.inputs()[0].input_type.tensor_dimensions().unwrap()[2] as u32;
-    let model_height = session.inputs()[0].input_type.tensor_dimensions().unwrap()[3] as u32;
+/// key of `yolo_inference` results for `image`, `model` is e.g. `CacheKey::model`
+pub fn yolo_cache_key(
+    model: CacheKeyBuilder,
+    image: &Image,
+    config: &YoloConfig,
+) -> CacheKey {
+    // hash map iteration order is not stable across runs
+    let mut class_thresholds = config.class_thresholds.iter().collect::<Vec<_>>();
+    class_thresholds.sort_by_key(|(class_id, _)| **class_id);
+
+    model
+        .debug(&config.preprocess)
+        .debug(&(config.iou_threshold, config.confidence_threshold, class_thresholds))
+        .debug(&(&config.classes, config.max_detections, config.min_box_area))
+        .debug(&config.labels)
+        .image(image)
+        .finish()
+}
+
+/// runs all images in as few session calls as the model's batch dimension allows, returning detections per image
+pub fn yolo_inference_batch(
+    session: &OrtSession,
+    images: &[&Image],
+    config: &YoloConfig,
+) -> Vec<Vec<BoundingBox>> {
+    yolo_inference_batch_with_handle(session, images, config, None).unwrap()
+}
 
-    let input = prepare_input(image, model_width, model_height);
+/// `yolo_inference_batch` with each session run controlled by `handle`
+pub fn yolo_inference_batch_with_handle(
+    session: &OrtSession,
+    images: &[&Image],
+    config: &YoloConfig,
+    handle: Option<&InferenceHandle>,
+) -> Result<Vec<Vec<BoundingBox>>, BevyOrtError> {
+    let dimensions = session.inputs()
+        .first()
+        .and_then(|input| input.input_type.tensor_dimensions())
+        .ok_or_else(|| BevyOrtError::InvalidModel("yolo model has no tensor input".to_string()))?
+        .clone();
+
+    // static batch exports only accept their exported batch size
+    let batch_size = match dimensions[0] {
+        batch_size if batch_size > 0 => batch_size as usize,
+        _ => images.len().max(1),
+    };
+
+    let mut batch_detections = Vec::with_capacity(images.len());
+
+    for chunk in images.chunks(batch_size) {
+        let size = input_size(&dimensions, chunk, &config.preprocess);
+        let (input, transforms) = prepare_padded_batch_input(chunk, batch_size, size, config.preprocess.resize);
+
+        let input_values = inputs!["images" => &input.as_standard_layout()]?;
+        let outputs = session.run_with(input_values, handle)?;
+        let output_value: &ort::Value = outputs.get("output0")
+            .ok_or_else(|| BevyOrtError::MissingOutput("output0".to_string()))?;
+
+        batch_detections.extend(
+            process_batch_output(output_value, &transforms, config)?
+                .into_iter()
+                .map(|detections| {
+                    let mut detections = nms(&detections, config.iou_threshold);
+
+                    if let Some(max_detections) = config.max_detections {
+                        detections.sort_by(|a, b| b.prob.partial_cmp(&a.prob).unwrap_or(std::cmp::Ordering::Equal));
+                        detections.truncate(max_detections);
+                    }
+
+                    if let Some(labels) = config.labels.as_ref() {
+                        for bbox in detections.iter_mut() {
+                            bbox.label = labels.get(bbox.class_id).map(String::from);
+                        }
+                    }
+
+                    detections
+                })
+        );
+    }
 
-    let input_values = inputs!["images" => &input.as_standard_layout()].map_err(|e| e.to_string()).unwrap();
-    let outputs = session.run(input_values).map_err(|e| e.to_string());
-    let binding = outputs.ok().unwrap();
-    let output_value: &ort::Value = binding.get("output0").unwrap();
+    Ok(batch_detections)
+}
 
-    let detections = process_output(output_value, width, height, model_width, model_height);
 
-    nms(&detections, iou_threshold)
+/// how source images are fit to the model input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum YoloResize {
+    /// keep the aspect ratio and pad the remaining area, as ultralytics does
+    Letterbox {
+        pad_color: [u8; 3],
+    },
+    /// resize width and height independently, distorting the aspect ratio
+    Stretch,
+}
+
+impl Default for YoloResize {
+    fn default() -> Self {
+        YoloResize::Letterbox {
+            pad_color: [114; 3],
+        }
+    }
+}
+
+/// model input parameters, used for dimensions the model leaves dynamic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct YoloPreprocess {
+    pub resize: YoloResize,
+    /// (width, height) images are fit into when the model input size is dynamic
+    pub inference_size: UVec2,
+    /// dynamic input dimensions are padded up to a multiple of this
+    pub stride: u32,
+}
+
+impl Default for YoloPreprocess {
+    fn default() -> Self {
+        Self {
+            resize: YoloResize::default(),
+            inference_size: UVec2::new(640, 640),
+            stride: 32,
+        }
+    }
+}
+
+/// model input sizes in (width, height) pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct YoloInputSize {
+    /// area images are fit into
+    pub target: UVec2,
+    /// size of the input tensor, letterboxed images are centered within it
+    pub canvas: UVec2,
+}
+
+impl YoloInputSize {
+    pub fn fixed(size: UVec2) -> Self {
+        Self {
+            target: size,
+            canvas: size,
+        }
+    }
+}
+
+/// resolves NCHW `dimensions` to an input size, filling dynamic (non-positive) dimensions from `preprocess`
+pub fn input_size(
+    dimensions: &[i64],
+    images: &[&Image],
+    preprocess: &YoloPreprocess,
+) -> YoloInputSize {
+    let fixed = |dimension: i64| (dimension > 0).then_some(dimension as u32);
+    let fixed_width = fixed(dimensions[3]);
+    let fixed_height = fixed(dimensions[2]);
+
+    let target = UVec2::new(
+        fixed_width.unwrap_or(preprocess.inference_size.x),
+        fixed_height.unwrap_or(preprocess.inference_size.y),
+    );
+
+    let stride = preprocess.stride.max(1);
+    let align = |value: u32| value.div_ceil(stride) * stride;
+
+    // ultralytics style minimal padding, the smallest stride aligned canvas that fits every image
+    let dynamic = match preprocess.resize {
+        YoloResize::Letterbox { .. } => images.iter()
+            .map(|image| letterbox_size(UVec2::new(image.width(), image.height()), target))
+            .fold(UVec2::ONE, UVec2::max),
+        YoloResize::Stretch => target,
+    };
+
+    let canvas = UVec2::new(
+        fixed_width.unwrap_or_else(|| align(dynamic.x)),
+        fixed_height.unwrap_or_else(|| align(dynamic.y)),
+    );
+
+    match preprocess.resize {
+        YoloResize::Letterbox { .. } => YoloInputSize {
+            target,
+            canvas,
+        },
+        YoloResize::Stretch => YoloInputSize::fixed(canvas),
+    }
+}
+
+
+/// maps source image pixels into model input pixels, `model = source * scale + offset`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct YoloTransform {
+    pub scale: Vec2,
+    pub offset: Vec2,
+    pub source_size: UVec2,
+}
+
+impl YoloTransform {
+    pub fn new(
+        resize: YoloResize,
+        source_size: UVec2,
+        size: YoloInputSize,
+    ) -> Self {
+        match resize {
+            YoloResize::Letterbox { .. } => {
+                let resized = letterbox_size(source_size, size.target);
+
+                Self {
+                    scale: resized.as_vec2() / source_size.as_vec2(),
+                    offset: ((size.canvas - resized) / 2).as_vec2(),
+                    source_size,
+                }
+            },
+            YoloResize::Stretch => Self {
+                scale: size.canvas.as_vec2() / source_size.as_vec2(),
+                offset: Vec2::ZERO,
+                source_size,
+            },
+        }
+    }
+
+    /// size of the resized image within the model input
+    pub fn resized_size(&self) -> UVec2 {
+        (self.source_size.as_vec2() * self.scale).round().as_uvec2()
+    }
+
+    pub fn to_source(&self, model: Vec2) -> Vec2 {
+        (model - self.offset) / self.scale
+    }
+}
+
+fn letterbox_size(source_size: UVec2, model_size: UVec2) -> UVec2 {
+    let ratio = (model_size.as_vec2() / source_size.as_vec2()).min_element();
+
+    (source_size.as_vec2() * ratio)
+        .round()
+        .as_uvec2()
+        .clamp(UVec2::ONE, model_size)
 }
 
 
@@ -63,59 +652,142 @@ pub fn prepare_input(
     image: &Image,
     model_width: u32,
     model_height: u32,
-) -> ArrayD<f32> {
-    let image = &image.clone().try_into_dynamic().unwrap();
-    let image = image.resize_exact(model_width, model_height, image::imageops::FilterType::CatmullRom);
-
-    let mut input = Array::zeros((1, 3, model_width as usize, model_height as usize)).into_dyn();
+    resize: YoloResize,
+) -> (ArrayD<f32>, YoloTransform) {
+    let size = YoloInputSize::fixed(UVec2::new(model_width, model_height));
+    let (input, mut transforms) = prepare_batch_input(&[image], size, resize);
+    (input, transforms.pop().unwrap())
+}
 
-    image.pixels().for_each(|(x, y, pixel)| {
-        let [r, g, b, _] = pixel.0;
-        let (x, y) = (x as usize, y as usize);
+/// stacks images into a (batch, 3, height, width) tensor
+pub fn prepare_batch_input(
+    images: &[&Image],
+    size: YoloInputSize,
+    resize: YoloResize,
+) -> (ArrayD<f32>, Vec<YoloTransform>) {
+    prepare_padded_batch_input(images, images.len(), size, resize)
+}
 
-        input[[0, 0, y, x]] = r as f32 / 255.0;
-        input[[0, 1, y, x]] = g as f32 / 255.0;
-        input[[0, 2, y, x]] = b as f32 / 255.0;
-    });
+/// pads the batch with empty images up to `batch_len`, for models exported with a fixed batch size
+///
+/// only the real images get a transform, so outputs of the padding are dropped.
+fn prepare_padded_batch_input(
+    images: &[&Image],
+    batch_len: usize,
+    size: YoloInputSize,
+    resize: YoloResize,
+) -> (ArrayD<f32>, Vec<YoloTransform>) {
+    let batch_len = batch_len.max(images.len());
+    let mut input = Array::zeros((batch_len, 3, size.canvas.y as usize, size.canvas.x as usize)).into_dyn();
+
+    if let YoloResize::Letterbox { pad_color } = resize {
+        for (c, value) in pad_color.iter().enumerate() {
+            input.index_axis_mut(Axis(1), c).fill(*value as f32 / 255.0);
+        }
+    }
 
-    input
+    let transforms = images.iter()
+        .enumerate()
+        .map(|(i, &image)| {
+            let transform = YoloTransform::new(
+                resize,
+                UVec2::new(image.width(), image.height()),
+                size,
+            );
+            let resized_size = transform.resized_size();
+            let offset = transform.offset.as_uvec2();
+
+            let image = &image.clone().try_into_dynamic().unwrap();
+            let image = image.resize_exact(resized_size.x, resized_size.y, image::imageops::FilterType::CatmullRom);
+
+            image.pixels().for_each(|(x, y, pixel)| {
+                let [r, g, b, _] = pixel.0;
+                let (x, y) = ((x + offset.x) as usize, (y + offset.y) as usize);
+
+                input[[i, 0, y, x]] = r as f32 / 255.0;
+                input[[i, 1, y, x]] = g as f32 / 255.0;
+                input[[i, 2, y, x]] = b as f32 / 255.0;
+            });
+
+            transform
+        })
+        .collect();
+
+    (input, transforms)
 }
 
 
 pub fn process_output(
     output: &ort::Value,
-    width: u32,
-    height: u32,
-    model_width: u32,
-    model_height: u32,
+    transform: &YoloTransform,
+    config: &YoloConfig,
+) -> Result<Vec<BoundingBox>, BevyOrtError> {
+    let detections = process_batch_output(output, &[*transform], config)?
+        .pop()
+        .unwrap_or_default();
+
+    Ok(detections)
+}
+
+/// splits `output0` by batch index, boxes are mapped back to source pixels through each image's transform
+pub fn process_batch_output(
+    output: &ort::Value,
+    transforms: &[YoloTransform],
+    config: &YoloConfig,
+) -> Result<Vec<Vec<BoundingBox>>, BevyOrtError> {
+    let tensor = output.try_extract_tensor::<f32>()?;
+    let tensor = tensor.view();
+
+    // outputs past `transforms` belong to padding images
+    let detections = transforms.iter()
+        .enumerate()
+        .map(|(i, transform)| process_detections(tensor.index_axis(Axis(0), i), transform, config))
+        .collect();
+
+    Ok(detections)
+}
+
+/// `output` is a single image's (4 + classes, anchors) prediction
+fn process_detections(
+    output: ArrayViewD<f32>,
+    transform: &YoloTransform,
+    config: &YoloConfig,
 ) -> Vec<BoundingBox> {
+    let (width, height) = (transform.source_size.x as f32, transform.source_size.y as f32);
     let mut boxes = Vec::new();
 
-    let tensor = output.try_extract_tensor::<f32>().unwrap();
-    let data = tensor.view().t().into_owned();
+    let data = output.t();
 
-    for detection  in data.axis_iter(Axis(0)) {
-        let detection : Vec<_> = detection.iter().collect();
+    for detection in data.axis_iter(Axis(0)) {
+        let detection: Vec<_> = detection.iter().collect();
 
-        let (class_id, prob) = detection.iter()
+        let best = detection.iter()
             .skip(4)
             .enumerate()
-            .reduce(|acc, row| if row.1 > acc.1 { row } else { acc })
-            .unwrap();
+            .reduce(|acc, row| if row.1 > acc.1 { row } else { acc });
 
-        if **prob < 0.5 {
+        let Some((class_id, prob)) = best else {
+            continue;
+        };
+
+        // filter after the argmax, a filtered class must not promote the box to another class
+        if !config.classes.allows(class_id) || **prob < config.threshold(class_id) {
             continue;
         }
 
-        let xc = detection[0] / model_width as f32 * width as f32;
-        let yc = detection[1] / model_height as f32 * height as f32;
-        let w = detection[2] / model_width as f32 * width as f32;
-        let h = detection[3] / model_height as f32 * height as f32;
+        let (xc, yc, w, h) = (*detection[0], *detection[1], *detection[2], *detection[3]);
+
+        let min = transform.to_source(Vec2::new(xc - w / 2.0, yc - h / 2.0));
+        let max = transform.to_source(Vec2::new(xc + w / 2.0, yc + h / 2.0));
 
-        let x1 = (xc - w / 2.0).max(0.0);
-        let y1 = (yc - h / 2.0).max(0.0);
-        let x2 = (xc + w / 2.0).min(width as f32);
-        let y2 = (yc + h / 2.0).min(height as f32);
+        let x1 = min.x.max(0.0);
+        let y1 = min.y.max(0.0);
+        let x2 = max.x.min(width);
+        let y2 = max.y.min(height);
+
+        if (x2 - x1).max(0.0) * (y2 - y1).max(0.0) < config.min_box_area {
+            continue;
+        }
 
         boxes.push(BoundingBox {
             x1,
@@ -124,6 +796,7 @@ pub fn process_output(
             y2,
             class_id,
             prob: **prob,
+            label: None,
         });
     }
 
@@ -267,8 +940,223 @@ pub const YOLO_CLASSES: [&str; 80] = [
 
 #[cfg(test)]
 mod tests {
+    use crate::test_utils::image;
+
     use super::*;
 
+    /// (batch, 4 + classes, anchors), image `i` detects class `i` in its first anchor
+    fn batch_output(batch_size: usize) -> ort::Value {
+        let mut output = Array::zeros((batch_size, 6, 3)).into_dyn();
+        for i in 0..batch_size {
+            output[[i, 0, 0]] = 32.0;
+            output[[i, 1, 0]] = 32.0;
+            output[[i, 2, 0]] = 16.0;
+            output[[i, 3, 0]] = 16.0;
+            output[[i, 4 + i % 2, 0]] = 0.9;
+        }
+
+        ort::Value::from_array(output).unwrap()
+    }
+
+    #[test]
+    fn test_batch_detections_are_split_per_image() {
+        let size = YoloInputSize::fixed(UVec2::new(64, 64));
+        let transform = YoloTransform::new(YoloResize::Stretch, UVec2::new(128, 128), size);
+
+        let detections = process_batch_output(
+            &batch_output(2),
+            &[transform, transform],
+            &YoloConfig::default(),
+        ).unwrap();
+
+        assert_eq!(detections.len(), 2, "each image should get its own detections.");
+        assert_eq!(detections[0].len(), 1, "first image should have one detection.");
+        assert_eq!(detections[0][0].class_id, 0, "first image detection should keep its class.");
+        assert_eq!(detections[1][0].class_id, 1, "second image detection should keep its class.");
+        assert_eq!(detections[1][0].x1, 48.0, "boxes should be scaled to the source image.");
+    }
+
+    #[test]
+    fn test_padding_image_detections_are_dropped() {
+        // a fixed batch size of 4 run with 3 images, the last output belongs to the padding image
+        let size = YoloInputSize::fixed(UVec2::new(64, 64));
+        let transforms = [
+            YoloTransform::new(YoloResize::Stretch, UVec2::new(64, 64), size),
+            YoloTransform::new(YoloResize::Stretch, UVec2::new(128, 128), size),
+            YoloTransform::new(YoloResize::Stretch, UVec2::new(256, 256), size),
+        ];
+
+        let detections = process_batch_output(&batch_output(4), &transforms, &YoloConfig::default()).unwrap();
+
+        assert_eq!(detections.len(), 3, "padding image outputs should be dropped.");
+        assert!(detections.iter().all(|detections| detections.len() == 1), "every real image should keep its detection.");
+        assert_eq!(detections[2][0].x1, 96.0, "each image should use its own transform.");
+    }
+
+    #[test]
+    fn test_letterbox_maps_boxes_to_source() {
+        let size = YoloInputSize::fixed(UVec2::new(640, 640));
+        let transform = YoloTransform::new(YoloResize::default(), UVec2::new(1280, 720), size);
+        assert_eq!(transform.resized_size(), UVec2::new(640, 360), "letterbox should keep the aspect ratio.");
+        assert_eq!(transform.offset, Vec2::new(0.0, 140.0), "letterbox should center the padding.");
+
+        let mut output = Array::zeros((1, 5, 1)).into_dyn();
+        output[[0, 0, 0]] = 320.0;
+        output[[0, 1, 0]] = 320.0;
+        output[[0, 2, 0]] = 64.0;
+        output[[0, 3, 0]] = 64.0;
+        output[[0, 4, 0]] = 0.9;
+
+        let detections = process_detections(output.index_axis(Axis(0), 0), &transform, &YoloConfig::default());
+        let bbox = &detections[0];
+        assert_eq!((bbox.x1, bbox.y1, bbox.x2, bbox.y2), (576.0, 296.0, 704.0, 424.0), "box should be mapped through the padding and scale.");
+    }
+
+    #[test]
+    fn test_rectangular_model_input() {
+        let source = image(1280, 720);
+        let preprocess = YoloPreprocess::default();
+
+        let size = input_size(&[1, 3, 384, 640], &[&source], &preprocess);
+        assert_eq!(size, YoloInputSize::fixed(UVec2::new(640, 384)), "NCHW dimensions should be read as (height, width).");
+
+        let (input, transforms) = prepare_batch_input(&[&source], size, preprocess.resize);
+        assert_eq!(input.shape(), &[1, 3, 384, 640], "input tensor should be (batch, 3, height, width).");
+        assert_eq!(transforms[0].offset, Vec2::new(0.0, 12.0), "letterbox padding should be split between top and bottom.");
+        assert_eq!(input[[0, 0, 0, 0]], 114.0 / 255.0, "padding should use the pad color.");
+        assert_eq!(input[[0, 0, 12, 0]], 1.0, "image content should start after the padding.");
+
+        let (input, _) = prepare_batch_input(&[&source], size, YoloResize::Stretch);
+        assert!(input.iter().all(|&v| v == 1.0), "stretch should fill the rectangular input.");
+    }
+
+    #[test]
+    fn test_fixed_batch_is_padded() {
+        let source = image(64, 32);
+        let size = YoloInputSize::fixed(UVec2::new(64, 64));
+
+        let (input, transforms) = prepare_padded_batch_input(&[&source], 4, size, YoloResize::default());
+        assert_eq!(input.shape(), &[4, 3, 64, 64], "input should match the fixed batch size.");
+        assert_eq!(transforms.len(), 1, "only real images should be mapped back to detections.");
+        assert!(
+            input.index_axis(Axis(0), 3).iter().all(|&v| v == 114.0 / 255.0),
+            "padding images should use the pad color.",
+        );
+    }
+
+    #[test]
+    fn test_dynamic_model_input_is_stride_aligned() {
+        let landscape = image(1280, 720);
+        let portrait = image(300, 600);
+        let preprocess = YoloPreprocess::default();
+
+        let size = input_size(&[-1, 3, -1, -1], &[&landscape], &preprocess);
+        assert_eq!(size.canvas, UVec2::new(640, 384), "dynamic inputs should pad to the next stride multiple.");
+
+        let size = input_size(&[-1, 3, -1, -1], &[&landscape, &portrait], &preprocess);
+        assert_eq!(size.canvas, UVec2::new(640, 640), "batched dynamic inputs should share a canvas that fits every image.");
+    }
+
+    #[test]
+    fn test_config_filters_detections() {
+        // (batch, 4 + classes, anchors), a large class 0 box and a small class 1 box
+        let mut output = Array::zeros((1, 6, 2)).into_dyn();
+        for (anchor, size, class, prob) in [(0, 32.0, 4, 0.6), (1, 4.0, 5, 0.4)] {
+            output[[0, 0, anchor]] = 32.0;
+            output[[0, 1, anchor]] = 32.0;
+            output[[0, 2, anchor]] = size;
+            output[[0, 3, anchor]] = size;
+            output[[0, class, anchor]] = prob;
+        }
+
+        let transform = YoloTransform::new(YoloResize::Stretch, UVec2::new(64, 64), YoloInputSize::fixed(UVec2::new(64, 64)));
+        let detect = |config: &YoloConfig| process_detections(output.index_axis(Axis(0), 0), &transform, config)
+            .iter()
+            .map(|bbox| bbox.class_id)
+            .collect::<Vec<_>>();
+
+        assert_eq!(detect(&YoloConfig::default()), vec![0], "default confidence threshold should drop the low probability box.");
+
+        let config = YoloConfig {
+            class_thresholds: HashMap::from([(1, 0.3)]),
+            ..default()
+        };
+        assert_eq!(detect(&config), vec![0, 1], "per-class thresholds should override the confidence threshold.");
+
+        let config = YoloConfig {
+            class_thresholds: HashMap::from([(1, 0.3)]),
+            classes: ClassFilter::Deny(vec![0]),
+            ..default()
+        };
+        assert_eq!(detect(&config), vec![1], "denied classes should be skipped.");
+
+        let config = YoloConfig {
+            class_thresholds: HashMap::from([(1, 0.3)]),
+            min_box_area: 100.0,
+            ..default()
+        };
+        assert_eq!(detect(&config), vec![0], "boxes below the minimum area should be dropped.");
+    }
+
+    #[test]
+    fn test_cache_key_ignores_class_threshold_order() {
+        let config = |thresholds: &[(usize, f32)]| YoloConfig {
+            class_thresholds: thresholds.iter().copied().collect(),
+            ..default()
+        };
+        let key = |config: &YoloConfig| yolo_cache_key(CacheKey::builder("yolo"), &image(8, 8), config);
+
+        let thresholds = (0..16).map(|class_id| (class_id, 0.25)).collect::<Vec<_>>();
+        let reversed = thresholds.iter().rev().copied().collect::<Vec<_>>();
+
+        assert_eq!(key(&config(&thresholds)), key(&config(&reversed)), "class threshold order should not change the key.");
+        assert_ne!(key(&config(&thresholds)), key(&config(&[])), "class thresholds should change the key.");
+    }
+
+    #[test]
+    fn test_denied_argmax_class_drops_the_box() {
+        // (batch, 4 + classes, anchors), one box scoring class 0 above class 1
+        let mut output = Array::zeros((1, 6, 1)).into_dyn();
+        for (row, value) in [(0, 32.0), (1, 32.0), (2, 32.0), (3, 32.0), (4, 0.9), (5, 0.6)] {
+            output[[0, row, 0]] = value;
+        }
+
+        let transform = YoloTransform::new(YoloResize::Stretch, UVec2::new(64, 64), YoloInputSize::fixed(UVec2::new(64, 64)));
+        let config = YoloConfig {
+            classes: ClassFilter::Deny(vec![0]),
+            ..default()
+        };
+
+        let boxes = process_detections(output.index_axis(Axis(0), 0), &transform, &config);
+        assert!(boxes.is_empty(), "a box whose best class is denied should be dropped, not relabelled.");
+
+        let config = YoloConfig {
+            classes: ClassFilter::Allow(vec![1]),
+            ..default()
+        };
+
+        let boxes = process_detections(output.index_axis(Axis(0), 0), &transform, &config);
+        assert!(boxes.is_empty(), "a box whose best class is not allowed should be dropped, not relabelled.");
+    }
+
+    #[test]
+    fn test_labels_parse_text_yaml_and_metadata() {
+        let text = YoloLabels::parse("cat\n\ndog\n").unwrap();
+        assert_eq!(text.names, vec!["cat", "dog"], "text labels should have one name per line.");
+
+        let yaml = YoloLabels::parse("path: data\nnames:\n  0: cat\n  2: 'hot dog'\nnc: 3\n").unwrap();
+        assert_eq!(yaml.names, vec!["cat", "1", "hot dog"], "yaml names mapping should be indexed by class id.");
+
+        let list = YoloLabels::parse("names:\n- cat\n- dog\n").unwrap();
+        assert_eq!(list.names, vec!["cat", "dog"], "yaml names list should be read in order.");
+
+        let metadata = YoloLabels::parse("{0: 'person', 1: \"traffic, light\"}").unwrap();
+        assert_eq!(metadata.get(1), Some("traffic, light"), "metadata names should keep quoted commas.");
+        assert_eq!(metadata.get(2), None, "unknown class ids should have no label.");
+
+        assert_eq!(YoloLabels::coco().get(0), Some("person"), "coco labels should match YOLO_CLASSES.");
+    }
+
     #[test]
     fn test_non_overlapping_boxes() {
         let a = BoundingBox {
@@ -278,6 +1166,7 @@ mod tests {
             y2: 1.0,
             class_id: 0,
             prob: 0.9,
+            label: None,
         };
 
         let b = BoundingBox {
@@ -287,6 +1176,7 @@ mod tests {
             y2: 3.0,
             class_id: 0,
             prob: 0.8,
+            label: None,
         };
 
         let filtered_boxes = nms(&[a.clone(), b.clone()], 0.5);
@@ -304,6 +1194,7 @@ mod tests {
             y2: 2.0,
             class_id: 0,
             prob: 0.9,
+            label: None,
         };
 
         let b = BoundingBox {
@@ -313,6 +1204,7 @@ mod tests {
             y2: 3.0,
             class_id: 0,
             prob: 0.8,
+            label: None,
         };
 
         let expected_iou = 1.0 / 7.0;
@@ -333,6 +1225,7 @@ mod tests {
             y2: 2.0,
             class_id: 0,
             prob: 0.9,
+            label: None,
         };
 
         let b = BoundingBox {
@@ -342,6 +1235,7 @@ mod tests {
             y2: 3.0,
             class_id: 1,
             prob: 0.8,
+            label: None,
         };
 
         let filtered_boxes = nms(&[a, b], 0.5);
@@ -357,6 +1251,7 @@ mod tests {
             y2: 2.0,
             class_id: 0,
             prob: 0.9,
+            label: None,
         };
 
         let b = BoundingBox {
@@ -366,6 +1261,7 @@ mod tests {
             y2: 2.0,
             class_id: 0,
             prob: 0.8,
+            label: None,
         };
 
         let expected_iou = 1.0;
@@ -381,6 +1277,7 @@ mod tests {
             y2: 2.0,
             class_id: 0,
             prob: 0.9,
+            label: None,
         };
 
         let b = BoundingBox {
@@ -390,6 +1287,7 @@ mod tests {
             y2: 4.0,
             class_id: 0,
             prob: 0.8,
+            label: None,
         };
 
         let expected_iou = 0.0;
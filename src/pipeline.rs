@@ -0,0 +1,671 @@
+use std::{
+    any::Any,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::{
+    prelude::*,
+    core::FrameCount,
+    utils::HashSet,
+};
+use thiserror::Error;
+
+use crate::{
+    Onnx,
+    OrtSession,
+    policy::{
+        InferencePolicy,
+        InferenceStaleness,
+    },
+};
+
+
+/// chains models as a DAG of stages, e.g. yolo person boxes -> crop -> modnet matting
+///
+/// each stage optionally runs a pre adapter over its inputs, an ONNX model, and a post adapter.
+/// values are passed between stages as shared `PipelineValue`s, so images and tensors are not cloned.
+/// stages run as soon as their inputs are ready, stages with an `InferencePolicy` are re-run on its cadence.
+pub struct PipelinePlugin;
+impl Plugin for PipelinePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<Pipeline>();
+        app.add_systems(PreUpdate, pipeline_system);
+    }
+}
+
+
+/// type-erased, cheaply cloneable value flowing between stages
+#[derive(Clone)]
+pub struct PipelineValue(Arc<dyn Any + Send + Sync>);
+
+impl PipelineValue {
+    pub fn new<T: Any + Send + Sync>(value: T) -> Self {
+        Self(Arc::new(value))
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.0.downcast_ref::<T>()
+    }
+
+    pub fn get<T: Any>(&self) -> Result<&T, String> {
+        self.downcast_ref::<T>()
+            .ok_or_else(|| format!("expected pipeline value of type {}", std::any::type_name::<T>()))
+    }
+}
+
+
+pub type PreAdapter = Arc<dyn Fn(&[&PipelineValue]) -> Result<PipelineValue, String> + Send + Sync>;
+pub type InferAdapter = Arc<dyn Fn(&OrtSession, &PipelineValue) -> Result<PipelineValue, String> + Send + Sync>;
+pub type PostAdapter = Arc<dyn Fn(&PipelineValue) -> Result<PipelineValue, String> + Send + Sync>;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StageId(usize);
+
+impl StageId {
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StageInput {
+    /// the entity's `PipelineInput::value`
+    Source,
+    Stage(StageId),
+}
+
+
+#[derive(Clone)]
+pub struct PipelineStage {
+    pub name: String,
+    pub inputs: Vec<StageInput>,
+    pub onnx: Option<Handle<Onnx>>,
+    pub pre: Option<PreAdapter>,
+    pub infer: Option<InferAdapter>,
+    pub post: Option<PostAdapter>,
+    /// re-runs the stage and its dependents once complete, stages without a policy run once per input
+    pub policy: Option<InferencePolicy>,
+}
+
+impl PipelineStage {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            inputs: vec![StageInput::Source],
+            onnx: None,
+            pre: None,
+            infer: None,
+            post: None,
+            policy: None,
+        }
+    }
+
+    pub fn with_inputs(mut self, inputs: impl IntoIterator<Item = StageInput>) -> Self {
+        self.inputs = inputs.into_iter().collect();
+        self
+    }
+
+    pub fn with_model(
+        mut self,
+        onnx: Handle<Onnx>,
+        infer: impl Fn(&OrtSession, &PipelineValue) -> Result<PipelineValue, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.onnx = Some(onnx);
+        self.infer = Some(Arc::new(infer));
+        self
+    }
+
+    pub fn with_pre(
+        mut self,
+        pre: impl Fn(&[&PipelineValue]) -> Result<PipelineValue, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.pre = Some(Arc::new(pre));
+        self
+    }
+
+    pub fn with_post(
+        mut self,
+        post: impl Fn(&PipelineValue) -> Result<PipelineValue, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.post = Some(Arc::new(post));
+        self
+    }
+
+    /// `EveryNFrames` and `MaxHz` are measured from the stage's latest result, input changes already reset the
+    /// whole pipeline and image modifications are not tracked per stage
+    pub fn with_policy(mut self, policy: InferencePolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+}
+
+
+#[derive(Debug, Clone, Error)]
+pub enum PipelineError {
+    #[error("stage {stage} references unknown input stage {input}")]
+    UnknownInput {
+        stage: String,
+        input: usize,
+    },
+    #[error("stage {stage} has {inputs} inputs and requires a pre adapter")]
+    MissingPreAdapter {
+        stage: String,
+        inputs: usize,
+    },
+    #[error("stage {stage} failed: {message}")]
+    Stage {
+        stage: String,
+        message: String,
+    },
+    #[error("stage {stage} skipped, upstream stage {upstream} failed")]
+    Upstream {
+        stage: String,
+        upstream: String,
+    },
+}
+
+
+/// stages in topological order, a stage may only consume the source or previously added stages
+#[derive(Asset, Clone, Default, TypePath)]
+pub struct Pipeline {
+    stages: Vec<PipelineStage>,
+}
+
+impl Pipeline {
+    pub fn add_stage(&mut self, stage: PipelineStage) -> Result<StageId, PipelineError> {
+        for input in &stage.inputs {
+            if let StageInput::Stage(id) = input {
+                if id.0 >= self.stages.len() {
+                    return Err(PipelineError::UnknownInput {
+                        stage: stage.name.clone(),
+                        input: id.0,
+                    });
+                }
+            }
+        }
+
+        if stage.inputs.len() != 1 && stage.pre.is_none() {
+            return Err(PipelineError::MissingPreAdapter {
+                stage: stage.name.clone(),
+                inputs: stage.inputs.len(),
+            });
+        }
+
+        self.stages.push(stage);
+        Ok(StageId(self.stages.len() - 1))
+    }
+
+    pub fn stages(&self) -> &[PipelineStage] {
+        &self.stages
+    }
+
+    /// runs every stage whose inputs are complete and whose model session is loaded
+    ///
+    /// failures are propagated to downstream stages as `PipelineError::Upstream`.
+    /// returns true if any stage changed state.
+    pub fn step(
+        &self,
+        source: &PipelineValue,
+        state: &mut PipelineState,
+        session: impl Fn(&Handle<Onnx>) -> Option<Arc<Mutex<Option<OrtSession>>>>,
+    ) -> bool {
+        state.stages.resize(self.stages.len(), StageState::Pending);
+
+        let mut progressed = false;
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            if !matches!(state.stages[index], StageState::Pending) {
+                continue;
+            }
+
+            let mut inputs = Vec::with_capacity(stage.inputs.len());
+            let mut blocked = false;
+            let mut upstream_failure = None;
+
+            for input in &stage.inputs {
+                match input {
+                    StageInput::Source => inputs.push(source),
+                    StageInput::Stage(id) => match &state.stages[id.0] {
+                        StageState::Complete(value) => inputs.push(value),
+                        StageState::Pending => blocked = true,
+                        StageState::Failed(_) => upstream_failure = Some(self.stages[id.0].name.clone()),
+                    },
+                }
+            }
+
+            if let Some(upstream) = upstream_failure {
+                state.stages[index] = StageState::Failed(PipelineError::Upstream {
+                    stage: stage.name.clone(),
+                    upstream,
+                });
+                progressed = true;
+                continue;
+            }
+
+            if blocked {
+                continue;
+            }
+
+            let session = match &stage.onnx {
+                Some(onnx) => match session(onnx) {
+                    Some(session) => Some(session),
+                    None => continue,
+                },
+                None => None,
+            };
+
+            let result = run_stage(stage, &inputs, session.as_deref());
+            state.stages[index] = match result {
+                Ok(Some(value)) => StageState::Complete(value),
+                Ok(None) => continue,
+                Err(message) => StageState::Failed(PipelineError::Stage {
+                    stage: stage.name.clone(),
+                    message,
+                }),
+            };
+            progressed = true;
+        }
+
+        progressed
+    }
+
+    /// resets complete stages whose policy is due, along with every stage depending on them
+    ///
+    /// returns true if any stage was reset.
+    pub fn reschedule(
+        &self,
+        state: &mut PipelineState,
+        frame: u32,
+        elapsed: Duration,
+    ) -> bool {
+        state.stages.resize(self.stages.len(), StageState::Pending);
+        state.staleness.resize(self.stages.len(), InferenceStaleness::default());
+
+        let mut reset = vec![false; self.stages.len()];
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            let upstream_reset = stage.inputs.iter().any(|input| match input {
+                StageInput::Source => false,
+                StageInput::Stage(id) => reset[id.0],
+            });
+
+            let staleness = &mut state.staleness[index];
+            let due = match (&stage.policy, staleness.last_run_frame, staleness.last_run_elapsed) {
+                (Some(policy), Some(last_frame), Some(last_elapsed)) => {
+                    staleness.frames_since_run = frame.wrapping_sub(last_frame);
+                    staleness.age = elapsed.saturating_sub(last_elapsed);
+                    policy.should_run(Some(staleness), false)
+                },
+                _ => false,
+            };
+
+            if upstream_reset || due {
+                reset[index] = !matches!(state.stages[index], StageState::Pending);
+                state.stages[index] = StageState::Pending;
+                state.staleness[index] = InferenceStaleness::default();
+            }
+        }
+
+        reset.into_iter().any(|reset| reset)
+    }
+}
+
+/// returns `Ok(None)` while the stage's session is not yet available, before running any adapter
+fn run_stage(
+    stage: &PipelineStage,
+    inputs: &[&PipelineValue],
+    session: Option<&Mutex<Option<OrtSession>>>,
+) -> Result<Option<PipelineValue>, String> {
+    let session_lock = match (session, &stage.infer) {
+        (Some(session), Some(_)) => Some(session.lock().map_err(|e| e.to_string())?),
+        _ => None,
+    };
+
+    let session = match session_lock.as_ref() {
+        Some(session_lock) => match session_lock.as_ref() {
+            Some(session) => Some(session),
+            None => return Ok(None),
+        },
+        None => None,
+    };
+
+    let value = match &stage.pre {
+        Some(pre) => pre(inputs)?,
+        None => inputs[0].clone(),
+    };
+
+    let value = match (session, &stage.infer) {
+        (Some(session), Some(infer)) => infer(session, &value)?,
+        _ => value,
+    };
+
+    match &stage.post {
+        Some(post) => post(&value).map(Some),
+        None => Ok(Some(value)),
+    }
+}
+
+
+#[derive(Clone)]
+pub enum StageState {
+    Pending,
+    Complete(PipelineValue),
+    Failed(PipelineError),
+}
+
+
+#[derive(Component, Clone)]
+pub struct PipelineInput {
+    pub pipeline: Handle<Pipeline>,
+    pub value: PipelineValue,
+}
+
+/// per-stage results for a `PipelineInput`, reset whenever the input or the pipeline asset changes
+#[derive(Component, Clone, Default)]
+pub struct PipelineState {
+    pub stages: Vec<StageState>,
+    /// when each finished stage last ran, used by stage policies
+    pub staleness: Vec<InferenceStaleness>,
+}
+
+impl PipelineState {
+    /// records the run of stages which finished since the last call
+    pub fn mark_finished(&mut self, frame: u32, elapsed: Duration) {
+        self.staleness.resize(self.stages.len(), InferenceStaleness::default());
+
+        for (stage, staleness) in self.stages.iter().zip(self.staleness.iter_mut()) {
+            if !matches!(stage, StageState::Pending) && staleness.last_run_frame.is_none() {
+                staleness.mark_run(frame, elapsed);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.stages.clear();
+        self.staleness.clear();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !self.stages.is_empty() && self.stages.iter().all(|stage| !matches!(stage, StageState::Pending))
+    }
+
+    pub fn get(&self, stage: StageId) -> Option<&StageState> {
+        self.stages.get(stage.0)
+    }
+
+    pub fn value(&self, stage: StageId) -> Option<&PipelineValue> {
+        match self.get(stage) {
+            Some(StageState::Complete(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &PipelineError> {
+        self.stages.iter().filter_map(|stage| match stage {
+            StageState::Failed(error) => Some(error),
+            _ => None,
+        })
+    }
+}
+
+
+fn pipeline_system(
+    mut commands: Commands,
+    frame_count: Res<FrameCount>,
+    time: Res<Time>,
+    mut pipeline_events: EventReader<AssetEvent<Pipeline>>,
+    pipelines: Res<Assets<Pipeline>>,
+    onnx_assets: Res<Assets<Onnx>>,
+    mut pipeline_inputs: Query<
+        (
+            Entity,
+            Ref<PipelineInput>,
+            Option<&mut PipelineState>,
+        ),
+    >,
+) {
+    let modified: HashSet<AssetId<Pipeline>> = pipeline_events.read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    let frame = frame_count.0;
+    let elapsed = time.elapsed();
+
+    for (entity, pipeline_input, pipeline_state) in pipeline_inputs.iter_mut() {
+        let Some(pipeline) = pipelines.get(&pipeline_input.pipeline) else {
+            continue;
+        };
+
+        let session = |onnx: &Handle<Onnx>| {
+            onnx_assets.get(onnx).map(|onnx| onnx.session.clone())
+        };
+
+        match pipeline_state {
+            Some(mut pipeline_state) => {
+                let mut state = std::mem::take(pipeline_state.bypass_change_detection());
+
+                let mut changed = if pipeline_input.is_changed() || modified.contains(&pipeline_input.pipeline.id()) {
+                    state.clear();
+                    true
+                } else {
+                    pipeline.reschedule(&mut state, frame, elapsed)
+                };
+
+                if !state.is_finished() {
+                    changed |= pipeline.step(&pipeline_input.value, &mut state, session);
+                    state.mark_finished(frame, elapsed);
+                }
+
+                if changed {
+                    *pipeline_state = state;
+                } else {
+                    *pipeline_state.bypass_change_detection() = state;
+                }
+            },
+            None => {
+                let mut state = PipelineState::default();
+                pipeline.step(&pipeline_input.value, &mut state, session);
+                state.mark_finished(frame, elapsed);
+
+                commands.entity(entity)
+                    .insert(state);
+            },
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn no_session(_: &Handle<Onnx>) -> Option<Arc<Mutex<Option<OrtSession>>>> {
+        None
+    }
+
+    #[test]
+    fn test_stages_chain_values() {
+        let mut pipeline = Pipeline::default();
+
+        let double = pipeline.add_stage(
+            PipelineStage::new("double")
+                .with_post(|value| Ok(PipelineValue::new(value.get::<i32>()? * 2)))
+        ).unwrap();
+
+        let sum = pipeline.add_stage(
+            PipelineStage::new("sum")
+                .with_inputs([StageInput::Source, StageInput::Stage(double)])
+                .with_pre(|inputs| {
+                    let mut total = 0;
+                    for input in inputs {
+                        total += input.get::<i32>()?;
+                    }
+                    Ok(PipelineValue::new(total))
+                })
+        ).unwrap();
+
+        let mut state = PipelineState::default();
+        assert!(pipeline.step(&PipelineValue::new(3), &mut state, no_session));
+
+        assert!(state.is_finished());
+        assert_eq!(state.value(double).unwrap().get::<i32>(), Ok(&6));
+        assert_eq!(state.value(sum).unwrap().get::<i32>(), Ok(&9));
+    }
+
+    #[test]
+    fn test_failure_propagates_downstream() {
+        let mut pipeline = Pipeline::default();
+
+        let fail = pipeline.add_stage(
+            PipelineStage::new("fail")
+                .with_post(|_| Err("no person detected".to_string()))
+        ).unwrap();
+
+        let downstream = pipeline.add_stage(
+            PipelineStage::new("downstream")
+                .with_inputs([StageInput::Stage(fail)])
+        ).unwrap();
+
+        let mut state = PipelineState::default();
+        pipeline.step(&PipelineValue::new(()), &mut state, no_session);
+
+        assert!(state.is_finished());
+        assert!(matches!(state.get(fail), Some(StageState::Failed(PipelineError::Stage { .. }))));
+        assert!(matches!(state.get(downstream), Some(StageState::Failed(PipelineError::Upstream { .. }))));
+        assert_eq!(state.errors().count(), 2);
+    }
+
+    #[test]
+    fn test_model_stage_waits_for_session() {
+        let mut pipeline = Pipeline::default();
+
+        let model = pipeline.add_stage(
+            PipelineStage::new("model")
+                .with_model(Handle::default(), |_, value| Ok(value.clone()))
+        ).unwrap();
+
+        let mut state = PipelineState::default();
+        assert!(!pipeline.step(&PipelineValue::new(()), &mut state, no_session));
+        assert!(matches!(state.get(model), Some(StageState::Pending)));
+        assert!(!state.is_finished());
+    }
+
+    #[test]
+    fn test_pre_adapter_waits_for_session() {
+        let pre_runs = Arc::new(AtomicUsize::new(0));
+
+        let mut pipeline = Pipeline::default();
+        let model = pipeline.add_stage(
+            PipelineStage::new("model")
+                .with_pre({
+                    let pre_runs = pre_runs.clone();
+                    move |inputs| {
+                        pre_runs.fetch_add(1, Ordering::SeqCst);
+                        Ok(inputs[0].clone())
+                    }
+                })
+                .with_model(Handle::default(), |_, value| Ok(value.clone()))
+        ).unwrap();
+
+        // asset loaded, session not yet committed
+        let loading_session = |_: &Handle<Onnx>| Some(Arc::new(Mutex::new(None)));
+
+        let mut state = PipelineState::default();
+        assert!(!pipeline.step(&PipelineValue::new(()), &mut state, loading_session));
+        assert!(matches!(state.get(model), Some(StageState::Pending)));
+        assert_eq!(pre_runs.load(Ordering::SeqCst), 0, "pre adapter should not run before the session is available.");
+    }
+
+    fn counting_stage(name: &str, runs: &Arc<AtomicUsize>) -> PipelineStage {
+        let runs = runs.clone();
+        PipelineStage::new(name)
+            .with_post(move |value| {
+                runs.fetch_add(1, Ordering::SeqCst);
+                Ok(value.clone())
+            })
+    }
+
+    #[test]
+    fn test_stage_policy_reruns_dependents() {
+        let source_runs = Arc::new(AtomicUsize::new(0));
+        let periodic_runs = Arc::new(AtomicUsize::new(0));
+        let downstream_runs = Arc::new(AtomicUsize::new(0));
+
+        let mut pipeline = Pipeline::default();
+        let source = pipeline.add_stage(counting_stage("source", &source_runs)).unwrap();
+        let periodic = pipeline.add_stage(
+            counting_stage("periodic", &periodic_runs)
+                .with_inputs([StageInput::Stage(source)])
+                .with_policy(InferencePolicy::EveryNFrames(2))
+        ).unwrap();
+        pipeline.add_stage(
+            counting_stage("downstream", &downstream_runs)
+                .with_inputs([StageInput::Stage(periodic)])
+        ).unwrap();
+
+        let source_value = PipelineValue::new(());
+        let mut state = PipelineState::default();
+
+        for frame in 0..5 {
+            pipeline.reschedule(&mut state, frame, Duration::ZERO);
+            pipeline.step(&source_value, &mut state, no_session);
+            state.mark_finished(frame, Duration::ZERO);
+        }
+
+        assert_eq!(source_runs.load(Ordering::SeqCst), 1, "stages without a policy should run once.");
+        assert_eq!(periodic_runs.load(Ordering::SeqCst), 3, "periodic stage should run on frames 0, 2 and 4.");
+        assert_eq!(downstream_runs.load(Ordering::SeqCst), 3, "dependents should re-run with the periodic stage.");
+    }
+
+    #[test]
+    fn test_modified_pipeline_resets_state() {
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()));
+        app.init_asset::<Onnx>();
+        app.add_plugins(PipelinePlugin);
+
+        let mut pipeline = Pipeline::default();
+        pipeline.add_stage(counting_stage("count", &runs)).unwrap();
+        let pipeline = app.world.resource_mut::<Assets<Pipeline>>().add(pipeline);
+
+        app.world.spawn(PipelineInput {
+            pipeline: pipeline.clone(),
+            value: PipelineValue::new(()),
+        });
+
+        app.update();
+        app.update();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // asset events are flushed at the end of the frame
+        app.world.resource_mut::<Assets<Pipeline>>().get_mut(&pipeline);
+        app.update();
+        app.update();
+        assert_eq!(runs.load(Ordering::SeqCst), 2, "modifying the pipeline should re-run its stages.");
+    }
+
+    #[test]
+    fn test_invalid_stages_are_rejected() {
+        let mut pipeline = Pipeline::default();
+
+        assert!(matches!(
+            pipeline.add_stage(PipelineStage::new("dangling").with_inputs([StageInput::Stage(StageId(0))])),
+            Err(PipelineError::UnknownInput { .. }),
+        ));
+
+        assert!(matches!(
+            pipeline.add_stage(PipelineStage::new("merge").with_inputs([StageInput::Source, StageInput::Source])),
+            Err(PipelineError::MissingPreAdapter { .. }),
+        ));
+    }
+}
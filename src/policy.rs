@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use bevy::{
+    prelude::*,
+    core::FrameCount,
+};
+
+
+/// tracks `InferenceStaleness` and image modifications for entities with an `InferencePolicy`
+///
+/// added by the plugins scheduling inference with policies, image modifications are only tracked
+/// when `Image` assets are registered.
+pub struct InferencePolicyPlugin;
+impl Plugin for InferencePolicyPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<InferencePolicy>();
+        app.register_type::<InferenceStaleness>();
+
+        app.add_systems(
+            PreUpdate,
+            (
+                update_inference_staleness,
+                mark_modified_images.run_if(resource_exists::<Events<AssetEvent<Image>>>),
+            )
+                .chain()
+                .in_set(InferencePolicySet),
+        );
+    }
+}
+
+impl InferencePolicyPlugin {
+    pub(crate) fn add_once(app: &mut App) {
+        if !app.is_plugin_added::<Self>() {
+            app.add_plugins(Self);
+        }
+    }
+}
+
+/// model inference systems run after this set
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemSet)]
+pub struct InferencePolicySet;
+
+
+/// when an entity's inference is re-run, entities without a policy run once
+#[derive(Debug, Clone, Default, Component, Reflect)]
+pub enum InferencePolicy {
+    #[default]
+    Once,
+    EveryNFrames(u32),
+    MaxHz(f32),
+    /// run when the entity's input component changes, e.g. `FlameInput`
+    OnInputChange,
+    /// run when the image asset is modified
+    OnImageModified(Handle<Image>),
+}
+
+impl InferencePolicy {
+    pub fn should_run(
+        &self,
+        staleness: Option<&InferenceStaleness>,
+        input_changed: bool,
+    ) -> bool {
+        let Some(staleness) = staleness.filter(|staleness| staleness.last_run_frame.is_some()) else {
+            return true;
+        };
+
+        match self {
+            InferencePolicy::Once => false,
+            InferencePolicy::EveryNFrames(frames) => staleness.frames_since_run >= (*frames).max(1),
+            InferencePolicy::MaxHz(hz) => *hz > 0.0 && staleness.age.as_secs_f32() >= 1.0 / hz,
+            InferencePolicy::OnInputChange => input_changed || staleness.input_changed,
+            InferencePolicy::OnImageModified(_) => staleness.input_changed,
+        }
+    }
+}
+
+
+/// how stale the latest inference result of an entity is
+#[derive(Debug, Clone, Default, Component, Reflect)]
+pub struct InferenceStaleness {
+    pub last_run_frame: Option<u32>,
+    pub last_run_elapsed: Option<Duration>,
+    pub frames_since_run: u32,
+    pub age: Duration,
+    /// the input changed since the latest result
+    pub input_changed: bool,
+}
+
+impl InferenceStaleness {
+    pub fn ran(frame: u32, elapsed: Duration) -> Self {
+        let mut staleness = Self::default();
+        staleness.mark_run(frame, elapsed);
+        staleness
+    }
+
+    pub fn mark_run(&mut self, frame: u32, elapsed: Duration) {
+        self.last_run_frame = Some(frame);
+        self.last_run_elapsed = Some(elapsed);
+        self.frames_since_run = 0;
+        self.age = Duration::ZERO;
+        self.input_changed = false;
+    }
+}
+
+
+fn update_inference_staleness(
+    frame_count: Res<FrameCount>,
+    time: Res<Time>,
+    mut staleness: Query<&mut InferenceStaleness>,
+) {
+    for mut staleness in staleness.iter_mut() {
+        if let (Some(frame), Some(elapsed)) = (staleness.last_run_frame, staleness.last_run_elapsed) {
+            staleness.frames_since_run = frame_count.0.wrapping_sub(frame);
+            staleness.age = time.elapsed().saturating_sub(elapsed);
+        }
+    }
+}
+
+fn mark_modified_images(
+    mut asset_events: EventReader<AssetEvent<Image>>,
+    mut policies: Query<(&InferencePolicy, &mut InferenceStaleness)>,
+) {
+    for event in asset_events.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+
+        for (policy, mut staleness) in policies.iter_mut() {
+            if let InferencePolicy::OnImageModified(image) = policy {
+                if image.id() == *id {
+                    staleness.input_changed = true;
+                }
+            }
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plugin_runs_without_image_assets() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        InferencePolicyPlugin::add_once(&mut app);
+        InferencePolicyPlugin::add_once(&mut app);
+
+        app.update();
+    }
+
+    #[test]
+    fn test_policies_run_without_result() {
+        assert!(InferencePolicy::Once.should_run(None, false));
+        assert!(InferencePolicy::EveryNFrames(10).should_run(Some(&InferenceStaleness::default()), false));
+    }
+
+    #[test]
+    fn test_every_n_frames() {
+        let mut staleness = InferenceStaleness::ran(10, Duration::ZERO);
+        let policy = InferencePolicy::EveryNFrames(3);
+
+        staleness.frames_since_run = 2;
+        assert!(!policy.should_run(Some(&staleness), false));
+
+        staleness.frames_since_run = 3;
+        assert!(policy.should_run(Some(&staleness), false));
+    }
+
+    #[test]
+    fn test_max_hz() {
+        let mut staleness = InferenceStaleness::ran(0, Duration::ZERO);
+        let policy = InferencePolicy::MaxHz(10.0);
+
+        staleness.age = Duration::from_millis(50);
+        assert!(!policy.should_run(Some(&staleness), false));
+
+        staleness.age = Duration::from_millis(100);
+        assert!(policy.should_run(Some(&staleness), false));
+    }
+
+    #[test]
+    fn test_on_change() {
+        let mut staleness = InferenceStaleness::ran(0, Duration::ZERO);
+
+        assert!(!InferencePolicy::Once.should_run(Some(&staleness), true));
+        assert!(!InferencePolicy::OnInputChange.should_run(Some(&staleness), false));
+        assert!(InferencePolicy::OnInputChange.should_run(Some(&staleness), true));
+
+        staleness.input_changed = true;
+        assert!(InferencePolicy::OnImageModified(Handle::default()).should_run(Some(&staleness), false));
+    }
+}
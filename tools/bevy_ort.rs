@@ -0,0 +1,412 @@
+use std::{
+    cell::OnceCell,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use bevy::{
+    prelude::*,
+    render::render_asset::RenderAssetUsages,
+};
+use bevy_args::parse_args;
+use clap::{Args, Parser, Subcommand};
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+
+use bevy_ort::{
+    models::{
+        flame::{
+            flame_inference_with_handle,
+            FlameInput,
+            INDEX_BUFFER,
+        },
+        lightglue::lightglue_inference_with_handle,
+        modnet::{
+            modnet_inference_mattes,
+            BatchLayout,
+            MatteSize,
+            ModnetConfig,
+            ModnetNormalization,
+            ModnetTiling,
+        },
+        yolo_v8::{
+            yolo_inference_with_handle,
+            YoloConfig,
+            YoloLabels,
+            YoloPreprocess,
+            YoloResize,
+        },
+    },
+    execution_providers::ExecutionProviders,
+    session_builder,
+    CustomOperators,
+    OrtSession,
+};
+
+
+#[derive(Debug, Default, Serialize, Deserialize, Parser)]
+#[command(about = "headless bevy_ort batch inference over directories", version, long_about = None)]
+pub struct BevyOrtCli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Serialize, Deserialize, Subcommand)]
+pub enum Command {
+    /// write a PNG matte per input image
+    Modnet {
+        #[command(flatten)]
+        io: DirectoryArgs,
+
+        /// requires `--max-height`
+        #[arg(long, requires = "max_height")]
+        max_width: Option<u32>,
+
+        /// requires `--max-width`
+        #[arg(long, requires = "max_width")]
+        max_height: Option<u32>,
+
+        #[arg(long, default_value_t = 512)]
+        ref_size: u32,
+
+        /// normalize inputs with ImageNet mean and std instead of MODNet's symmetric normalization
+        #[arg(long)]
+        imagenet: bool,
+
+        /// run overlapping full resolution tiles, for large stills
+        #[arg(long)]
+        tiled: bool,
+    },
+    /// write JSON detections per input image
+    Yolo {
+        #[command(flatten)]
+        io: DirectoryArgs,
+
+        #[arg(long, default_value_t = 0.5)]
+        iou_threshold: f32,
+
+        #[arg(long, default_value_t = 0.5)]
+        confidence_threshold: f32,
+
+        /// labels text or YAML file, defaults to the model's `names` metadata
+        #[arg(long)]
+        labels: Option<PathBuf>,
+
+        /// stretch images to the model input instead of letterboxing
+        #[arg(long)]
+        stretch: bool,
+    },
+    /// write CSV matches for each consecutive pair of input images
+    Lightglue {
+        #[command(flatten)]
+        io: DirectoryArgs,
+    },
+    /// write an OBJ mesh per JSON parameter file
+    Flame {
+        #[command(flatten)]
+        io: DirectoryArgs,
+    },
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Command::Modnet {
+            io: DirectoryArgs::default(),
+            max_width: None,
+            max_height: None,
+            ref_size: 512,
+            imagenet: false,
+            tiled: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Args)]
+pub struct DirectoryArgs {
+    #[arg(long)]
+    pub input: PathBuf,
+
+    #[arg(long)]
+    pub output: PathBuf,
+
+    #[arg(long)]
+    pub model: PathBuf,
+}
+
+
+/// FLAME parameters, missing or short parameter lists are zero padded
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlameParameters {
+    pub shape: Vec<f32>,
+    pub pose: Vec<f32>,
+    pub expression: Vec<f32>,
+    pub neck: Vec<f32>,
+    pub eye: Vec<f32>,
+}
+
+impl From<FlameParameters> for FlameInput {
+    fn from(parameters: FlameParameters) -> Self {
+        fn copy(dst: &mut [f32], src: &[f32]) {
+            dst.iter_mut()
+                .zip(src)
+                .for_each(|(dst, src)| *dst = *src);
+        }
+
+        let mut input = FlameInput::default();
+        copy(&mut input.shape[0], &parameters.shape);
+        copy(&mut input.pose[0], &parameters.pose);
+        copy(&mut input.expression[0], &parameters.expression);
+        copy(&mut input.neck[0], &parameters.neck);
+        copy(&mut input.eye[0], &parameters.eye);
+        input
+    }
+}
+
+
+fn main() {
+    let args = parse_args::<BevyOrtCli>();
+
+    let result = match args.command {
+        Command::Modnet { io, max_width, max_height, ref_size, imagenet, tiled } => {
+            let max_size = max_width.zip(max_height);
+            let config = ModnetConfig {
+                ref_size,
+                normalization: if imagenet { ModnetNormalization::ImageNet } else { ModnetNormalization::Symmetric },
+                tiling: tiled.then(|| ModnetTiling {
+                    tile_size: ref_size,
+                    ..default()
+                }),
+                ..default()
+            };
+            run_images(&io, |session, path, image| {
+                let mask = modnet_inference_mattes(session, &[image], max_size, &config, MatteSize::Original(FilterType::Triangle), BatchLayout::Group)
+                    .map_err(|e| e.to_string())?
+                    .pop()
+                    .ok_or("modnet returned no mask")?
+                    .image;
+                let mask = image::GrayImage::from_raw(mask.width(), mask.height(), mask.data)
+                    .ok_or("invalid modnet mask")?;
+
+                mask.save(output_path(&io.output, path, "png")).map_err(|e| e.to_string())
+            })
+        },
+        Command::Yolo { io, iou_threshold, confidence_threshold, labels, stretch } => {
+            load_labels(labels.as_deref()).and_then(|labels| {
+                let config = YoloConfig {
+                    preprocess: YoloPreprocess {
+                        resize: if stretch { YoloResize::Stretch } else { YoloResize::default() },
+                        ..default()
+                    },
+                    iou_threshold,
+                    confidence_threshold,
+                    labels,
+                    ..default()
+                };
+
+                let resolved = OnceCell::new();
+                run_images(&io, |session, path, image| {
+                    let config = resolved.get_or_init(|| config.clone().with_model_labels(session));
+                    let detections = yolo_inference_with_handle(session, image, config, None).map_err(|e| e.to_string())?;
+                    let json = serde_json::to_string_pretty(&detections).map_err(|e| e.to_string())?;
+
+                    fs::write(output_path(&io.output, path, "json"), json).map_err(|e| e.to_string())
+                })
+            })
+        },
+        Command::Lightglue { io } => run_lightglue(&io),
+        Command::Flame { io } => run_flame(&io),
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+
+fn load_labels(path: Option<&Path>) -> Result<Option<YoloLabels>, String> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+
+    let text = fs::read_to_string(path).map_err(|e| format!("failed to read labels {}: {}", path.display(), e))?;
+    YoloLabels::parse(&text).map(Some)
+}
+
+fn load_session(model: &Path) -> Result<OrtSession, String> {
+    let mut execution_providers = ExecutionProviders::default();
+    execution_providers.init_environment();
+
+    let session = session_builder(&execution_providers, &CustomOperators::default())
+        .and_then(|(builder, report)| {
+            report.log(&model.display().to_string());
+            builder.commit_from_file(model)
+        })
+        .map_err(|e| format!("failed to load model {}: {}", model.display(), e))?;
+
+    Ok(OrtSession::Session(session))
+}
+
+fn list_files(directory: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>, String> {
+    let mut files = fs::read_dir(directory)
+        .map_err(|e| format!("failed to read {}: {}", directory.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| extensions.contains(&ext.to_lowercase().as_str()))
+        })
+        .collect::<Vec<_>>();
+
+    files.sort();
+    Ok(files)
+}
+
+fn load_image(path: &Path) -> Result<Image, String> {
+    let image = image::open(path).map_err(|e| e.to_string())?;
+    Ok(Image::from_dynamic(image, true, RenderAssetUsages::all()))
+}
+
+fn output_path(output: &Path, input: &Path, extension: &str) -> PathBuf {
+    let stem = input.file_stem().unwrap_or_default();
+    output.join(format!("{}.{}", stem.to_string_lossy(), extension))
+}
+
+
+struct Progress {
+    total: usize,
+    processed: usize,
+    failed: usize,
+    start: Instant,
+}
+
+impl Progress {
+    fn new(total: usize) -> Self {
+        Self {
+            total,
+            processed: 0,
+            failed: 0,
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, name: &str, result: Result<(), String>) {
+        self.processed += 1;
+
+        match result {
+            Ok(()) => println!("[{}/{}] {}", self.processed, self.total, name),
+            Err(e) => {
+                self.failed += 1;
+                eprintln!("[{}/{}] {} failed: {}", self.processed, self.total, name, e);
+            },
+        }
+
+        std::io::stdout().flush().ok();
+    }
+
+    /// prints the summary, failing the run when any file failed
+    fn finish(self) -> Result<(), String> {
+        let elapsed = self.start.elapsed();
+        println!(
+            "processed {} of {} ({} failed) in {:.2}s",
+            self.processed - self.failed,
+            self.total,
+            self.failed,
+            elapsed.as_secs_f32(),
+        );
+
+        match self.failed {
+            0 => Ok(()),
+            failed => Err(format!("{} of {} failed", failed, self.total)),
+        }
+    }
+}
+
+
+const IMAGE_EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
+
+fn run_images(
+    io: &DirectoryArgs,
+    infer: impl Fn(&OrtSession, &Path, &Image) -> Result<(), String>,
+) -> Result<(), String> {
+    let session = load_session(&io.model)?;
+    let files = list_files(&io.input, &IMAGE_EXTENSIONS)?;
+    fs::create_dir_all(&io.output).map_err(|e| e.to_string())?;
+
+    let mut progress = Progress::new(files.len());
+    for path in &files {
+        let result = load_image(path).and_then(|image| infer(&session, path, &image));
+        progress.record(&path.display().to_string(), result);
+    }
+
+    progress.finish()
+}
+
+fn run_lightglue(io: &DirectoryArgs) -> Result<(), String> {
+    let session = load_session(&io.model)?;
+    let files = list_files(&io.input, &IMAGE_EXTENSIONS)?;
+    fs::create_dir_all(&io.output).map_err(|e| e.to_string())?;
+
+    let mut progress = Progress::new(files.len().saturating_sub(1));
+    for pair in files.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+
+        let result = (|| {
+            let images = [load_image(a)?, load_image(b)?];
+            let (_, _, matches) = lightglue_inference_with_handle(&session, &[&images[0], &images[1]], None)
+                .map_err(|e| e.to_string())?
+                .pop()
+                .ok_or("lightglue returned no matches")?;
+
+            let mut csv = String::from("from_x,from_y,to_x,to_y\n");
+            for pair in matches {
+                csv.push_str(&format!("{},{},{},{}\n", pair.from_x, pair.from_y, pair.to_x, pair.to_y));
+            }
+
+            let a_stem = a.file_stem().unwrap_or_default().to_string_lossy();
+            let b_stem = b.file_stem().unwrap_or_default().to_string_lossy();
+            let path = io.output.join(format!("{}_{}.csv", a_stem, b_stem));
+
+            fs::write(path, csv).map_err(|e| e.to_string())
+        })();
+
+        progress.record(&format!("{} -> {}", a.display(), b.display()), result);
+    }
+
+    progress.finish()
+}
+
+fn run_flame(io: &DirectoryArgs) -> Result<(), String> {
+    let session = load_session(&io.model)?;
+    let files = list_files(&io.input, &["json"])?;
+    fs::create_dir_all(&io.output).map_err(|e| e.to_string())?;
+
+    let indices: &[u32] = bytemuck::cast_slice(INDEX_BUFFER);
+
+    let mut progress = Progress::new(files.len());
+    for path in &files {
+        let result = (|| {
+            let json = fs::read_to_string(path).map_err(|e| e.to_string())?;
+            let parameters: FlameParameters = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+
+            let output = flame_inference_with_handle(&session, &parameters.into(), None).map_err(|e| e.to_string())?;
+
+            let mut obj = String::new();
+            for [x, y, z] in &output.vertices {
+                obj.push_str(&format!("v {} {} {}\n", x, y, z));
+            }
+            for face in indices.chunks_exact(3) {
+                obj.push_str(&format!("f {} {} {}\n", face[0] + 1, face[1] + 1, face[2] + 1));
+            }
+
+            fs::write(output_path(&io.output, path, "obj"), obj).map_err(|e| e.to_string())
+        })();
+
+        progress.record(&path.display().to_string(), result);
+    }
+
+    progress.finish()
+}
@@ -3,8 +3,9 @@ use bevy::prelude::*;
 use bevy_ort::{
     BevyOrtPlugin,
     models::modnet::{
-        modnet_inference,
         Modnet,
+        ModnetInput,
+        ModnetOutput,
         ModnetPlugin,
     },
     Onnx,
@@ -19,85 +20,53 @@ fn main() {
             BevyOrtPlugin,
             ModnetPlugin,
         ))
-        .init_resource::<ModnetInput>()
         .add_systems(Startup, load_modnet)
-        .add_systems(Update, inference)
+        .add_systems(Update, display_matte)
         .run();
 }
 
-#[derive(Resource, Default)]
-struct ModnetInput {
-    image: Handle<Image>,
-}
-
 
 fn load_modnet(
+    mut commands: Commands,
     asset_server: Res<AssetServer>,
     mut modnet: ResMut<Modnet>,
-    mut input: ResMut<ModnetInput>,
 ) {
     let modnet_handle: Handle<Onnx> = asset_server.load("models/modnet_photographic_portrait_matting.onnx");
     modnet.onnx = modnet_handle;
 
-    let input_handle: Handle<Image> = asset_server.load("images/person.png");
-    input.image = input_handle;
+    commands.spawn(ModnetInput {
+        image: asset_server.load("images/person.png"),
+    });
 }
 
 
-fn inference(
+fn display_matte(
     mut commands: Commands,
-    modnet: Res<Modnet>,
-    input: Res<ModnetInput>,
-    onnx_assets: Res<Assets<Onnx>>,
-    mut images: ResMut<Assets<Image>>,
-    mut complete: Local<bool>,
+    modnet_outputs: Query<&ModnetOutput, Added<ModnetOutput>>,
 ) {
-    if *complete {
-        return;
-    }
-
-    let image = images.get(&input.image).expect("failed to get image asset");
-
-    let mask_image: Result<Image, String> = (|| {
-        let onnx = onnx_assets.get(&modnet.onnx).ok_or("failed to get ONNX asset")?;
-        let session_lock = onnx.session.lock().map_err(|e| e.to_string())?;
-        let session = session_lock.as_ref().ok_or("failed to get session from ONNX asset")?;
-
-        Ok(modnet_inference(session, &[image], None).pop().unwrap())
-    })();
-
-    match mask_image {
-        Ok(mask_image) => {
-            let mask_image = images.add(mask_image);
-
-            commands.spawn(NodeBundle {
+    for modnet_output in modnet_outputs.iter() {
+        commands.spawn(NodeBundle {
+            style: Style {
+                display: Display::Grid,
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                grid_template_columns: RepeatedGridTrack::flex(1, 1.0),
+                grid_template_rows: RepeatedGridTrack::flex(1, 1.0),
+                ..default()
+            },
+            background_color: BackgroundColor(Color::DARK_GRAY),
+            ..default()
+        })
+        .with_children(|builder| {
+            builder.spawn(ImageBundle {
                 style: Style {
-                    display: Display::Grid,
-                    width: Val::Percent(100.0),
-                    height: Val::Percent(100.0),
-                    grid_template_columns: RepeatedGridTrack::flex(1, 1.0),
-                    grid_template_rows: RepeatedGridTrack::flex(1, 1.0),
                     ..default()
                 },
-                background_color: BackgroundColor(Color::DARK_GRAY),
+                image: UiImage::new(modnet_output.matte.clone()),
                 ..default()
-            })
-            .with_children(|builder| {
-                builder.spawn(ImageBundle {
-                    style: Style {
-                        ..default()
-                    },
-                    image: UiImage::new(mask_image.clone()),
-                    ..default()
-                });
             });
+        });
 
-            commands.spawn(Camera2dBundle::default());
-
-            *complete = true;
-        }
-        Err(e) => {
-            eprintln!("inference failed: {}", e);
-        }
+        commands.spawn(Camera2dBundle::default());
     }
 }
@@ -73,7 +73,7 @@ fn inference(
         Ok(yolo_inference(
             session,
             image,
-            0.5,
+            &yolo.config,
         ))
     })();
 